@@ -0,0 +1,90 @@
+// Measure line throughput of the default parsers with and without the
+// shared literal prefilter, on a synthetic log of several hundred MB.
+//
+// Run with `cargo bench --bench prefilter`; set LOGPARSER_BENCH_MB to
+// change the log size.
+
+extern crate logparser;
+
+use logparser::limits::LimitState;
+use logparser::logparser::{LogParser, LogParserError};
+use logparser::{default_parsers, parse_reader};
+use std::env;
+use std::io::Cursor;
+use std::time::Instant;
+
+/// Wrapper that opts a parser out of prefiltering, so it sees every line.
+struct Unfiltered(Box<LogParser>);
+
+impl LogParser for Unfiltered {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn parse_line(&mut self, line: &str, line_number: u32) -> Result<(), LogParserError> {
+        self.0.parse_line(line, line_number)
+    }
+
+    fn get_artifact(&mut self) -> String {
+        self.0.get_artifact()
+    }
+
+    fn finish_parse(&mut self, last_line_number: u32) {
+        self.0.finish_parse(last_line_number)
+    }
+
+    fn complete(&self) -> bool {
+        self.0.complete()
+    }
+
+    fn has_artifact(&self) -> bool {
+        self.0.has_artifact()
+    }
+}
+
+fn make_log(size: usize) -> Vec<u8> {
+    let lines = [
+        "========= Started mochitest (results: 0, elapsed: 0 secs) (at 2016-04-14 20:00:00.000000) =========",
+        "12:34:56     INFO -  TEST-START | dom/tests/mochitest/test_example.html",
+        "12:34:56     INFO -  TEST-PASS | dom/tests/mochitest/test_example.html | assertion passed",
+        "12:34:56     INFO -  [Parent 1234] WARNING: NS_ENSURE_TRUE(mDocShell) failed: file nsDocShell.cpp, line 123",
+        "12:34:56     INFO -  --DOMWINDOW == 12 (0x7f0000000000) [pid = 1234] [serial = 56] [outer = 0x0]",
+        "12:34:56     INFO -  TEST-UNEXPECTED-FAIL | dom/tests/mochitest/test_example.html | got 1, expected 2",
+        "12:34:56     INFO -  TinderboxPrint: mochitest-plain<br/>1234/0/56",
+        "12:34:56     INFO -  PERFHERDER_DATA: {\"framework\": {\"name\": \"build_metrics\"}, \"suites\": []}",
+        "========= Finished mochitest (results: 1, elapsed: 10 secs) (at 2016-04-14 20:00:10.000000) =========",
+    ];
+    let mut data = Vec::with_capacity(size + 200);
+    let mut i = 0;
+    while data.len() < size {
+        // Mostly uninteresting lines, with the occasional interesting one
+        let line = if i % 100 < 5 { lines[(i / 100 + i) % lines.len()] } else { lines[2 + i % 3] };
+        data.extend_from_slice(line.as_bytes());
+        data.push(b'\n');
+        i += 1;
+    }
+    data
+}
+
+fn time(name: &str, data: &[u8], parsers: Vec<Box<LogParser>>) {
+    let start = Instant::now();
+    let (artifacts, _) = parse_reader(Cursor::new(data), parsers, &LimitState::unlimited(),
+                                      false).unwrap();
+    let elapsed = start.elapsed();
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1E9;
+    println!("{}: {:.3}s, {:.1} MB/s ({} artifacts)",
+             name, secs, data.len() as f64 / 1E6 / secs, artifacts.len());
+}
+
+fn main() {
+    let size_mb = env::var("LOGPARSER_BENCH_MB").ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(300);
+    let data = make_log(size_mb * 1000000);
+
+    let unfiltered = default_parsers().into_iter()
+        .map(|x| Box::new(Unfiltered(x)) as Box<LogParser>)
+        .collect();
+    time("without prefilter", &data, unfiltered);
+    time("with prefilter", &data, default_parsers());
+}
@@ -0,0 +1,66 @@
+// Compare the single-pass TinderboxPrint matcher against the previous
+// approach of calling is_match on each rule in turn. Both extract the data
+// from the matching rule, so they do the same work apart from the matching.
+//
+// Run with `cargo bench --bench tinderbox`.
+
+extern crate logparser;
+extern crate regex;
+
+use logparser::tinderboxparser::{default_rules, TinderboxMatcher, TinderboxRule};
+use regex::Regex;
+use std::time::Instant;
+
+static LINE_COUNT: usize = 2000000;
+
+fn make_log() -> Vec<String> {
+    let prints = [
+        "TinderboxPrint: TalosResult: {\"results\": [1, 2, 3]}",
+        "TinderboxPrint: <a href='https://example.org/build.tar.bz2'>build.tar.bz2</a>: uploaded",
+        "TinderboxPrint: Logs: <a title='logs' href=\"https://example.org/log\">log</a>",
+        "TinderboxPrint: Task: https://tools.taskcluster.net/task-inspector/#abc",
+        "TinderboxPrint: mochitest-plain<br/>1234/0/56",
+        "TinderboxPrint: Chunk 3 of 10",
+    ];
+    (0..LINE_COUNT).map(|i| {
+        if i % 50 == 0 {
+            prints[(i / 50) % prints.len()].into()
+        } else {
+            format!("12:34:56     INFO -  TEST-PASS | dom/tests/test_{}.html | ok", i)
+        }
+    }).collect()
+}
+
+/// Run the rule finder over the TinderboxPrint lines, extracting the data
+/// from the matching rule, and count the matches.
+fn extract_all<'a, F>(lines: &[String], find: F) -> usize
+    where F: Fn(&str) -> Option<&'a TinderboxRule> {
+    let tinderbox_print = Regex::new(r"TinderboxPrint: ?(?P<line>.*)$").unwrap();
+    let mut count = 0;
+    for line in lines.iter() {
+        if !tinderbox_print.is_match(line) {
+            continue;
+        }
+        let line = tinderbox_print.captures(line).unwrap().name("line").unwrap();
+        if let Some(rule) = find(line) {
+            rule.extract(line).unwrap();
+            count += 1;
+        }
+    }
+    count
+}
+
+fn main() {
+    let lines = make_log();
+    let rules = default_rules();
+    let matcher = TinderboxMatcher::new(default_rules()).unwrap();
+    time("sequential", || extract_all(&lines, |line| rules.iter().find(|x| x.is_match(line))));
+    time("single pass", || extract_all(&lines, |line| matcher.find(line)));
+}
+
+fn time<F: FnOnce() -> usize>(name: &str, f: F) {
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    println!("{}: {}.{:03}s ({})", name, elapsed.as_secs(), elapsed.subsec_nanos() / 1000000, result);
+}
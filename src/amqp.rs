@@ -0,0 +1,613 @@
+use daemon::{Delivery, JobSource};
+use logparser::LogParserError;
+use rustc_serialize::json;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use std::{i32, u32};
+
+// The subset of AMQP 0-9-1 needed to consume from one queue and publish
+// to another, on a single channel.
+static PROTOCOL_HEADER: &'static [u8] = b"AMQP\x00\x00\x09\x01";
+static FRAME_METHOD: u8 = 1;
+static FRAME_HEADER: u8 = 2;
+static FRAME_BODY: u8 = 3;
+static FRAME_HEARTBEAT: u8 = 8;
+static FRAME_END: u8 = 0xCE;
+static FRAME_MAX: u32 = 131072;
+static CHANNEL: u16 = 1;
+// Header recording the attempts at a job on requeued messages
+static ATTEMPTS_HEADER: &'static str = "x-logparser-attempts";
+
+static CONNECTION_START: (u16, u16) = (10, 10);
+static CONNECTION_START_OK: (u16, u16) = (10, 11);
+static CONNECTION_TUNE: (u16, u16) = (10, 30);
+static CONNECTION_TUNE_OK: (u16, u16) = (10, 31);
+static CONNECTION_OPEN: (u16, u16) = (10, 40);
+static CONNECTION_OPEN_OK: (u16, u16) = (10, 41);
+static CONNECTION_CLOSE: (u16, u16) = (10, 50);
+static CHANNEL_OPEN: (u16, u16) = (20, 10);
+static CHANNEL_OPEN_OK: (u16, u16) = (20, 11);
+static CHANNEL_CLOSE: (u16, u16) = (20, 40);
+static QUEUE_DECLARE: (u16, u16) = (50, 10);
+static QUEUE_DECLARE_OK: (u16, u16) = (50, 11);
+static BASIC_QOS: (u16, u16) = (60, 10);
+static BASIC_QOS_OK: (u16, u16) = (60, 11);
+static BASIC_CONSUME: (u16, u16) = (60, 20);
+static BASIC_CONSUME_OK: (u16, u16) = (60, 21);
+static BASIC_CANCEL: (u16, u16) = (60, 30);
+static BASIC_PUBLISH: (u16, u16) = (60, 40);
+static BASIC_RETURN: (u16, u16) = (60, 50);
+static BASIC_DELIVER: (u16, u16) = (60, 60);
+static BASIC_ACK: (u16, u16) = (60, 80);
+static BASIC_REJECT: (u16, u16) = (60, 90);
+static BASIC_NACK: (u16, u16) = (60, 120);
+static CONFIRM_SELECT: (u16, u16) = (85, 10);
+static CONFIRM_SELECT_OK: (u16, u16) = (85, 11);
+
+fn protocol_error(message: &str) -> LogParserError {
+    LogParserError::Other(format!("AMQP protocol error: {}", message))
+}
+
+/// Fail if a method frame is the broker closing the connection or channel.
+fn check_close(payload: &[u8]) -> Result<(u16, u16), LogParserError> {
+    let mut args = ArgReader::new(payload);
+    let method = try!(args.method());
+    if method == CONNECTION_CLOSE || method == CHANNEL_CLOSE {
+        let code = try!(args.short());
+        let text = try!(args.shortstr());
+        return Err(LogParserError::Other(format!("AMQP broker closed the connection: {} {}", code, text)));
+    }
+    if method == BASIC_CANCEL {
+        return Err(LogParserError::Other("AMQP broker cancelled the consumer".into()));
+    }
+    Ok(method)
+}
+
+pub struct AmqpConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    pub vhost: String,
+    /// Queue of job descriptors
+    pub queue: String,
+    /// Queue for jobs that fail, published to through the default exchange.
+    /// It is declared, as durable, when connecting.
+    pub dead_letter_queue: String,
+    /// Number of unacknowledged messages the broker will send at once
+    pub prefetch: u16,
+    /// How long to wait for a message before returning None
+    pub poll_interval: Duration
+}
+
+impl AmqpConfig {
+    pub fn new(host: &str, queue: &str, dead_letter_queue: &str) -> AmqpConfig {
+        AmqpConfig {
+            host: host.into(),
+            port: 5672,
+            user: "guest".into(),
+            password: "guest".into(),
+            vhost: "/".into(),
+            queue: queue.into(),
+            dead_letter_queue: dead_letter_queue.into(),
+            prefetch: 8,
+            poll_interval: Duration::from_millis(500)
+        }
+    }
+}
+
+/// Arguments of a method, or a content header, being written.
+struct ArgWriter {
+    buf: Vec<u8>
+}
+
+impl ArgWriter {
+    fn method(method: (u16, u16)) -> ArgWriter {
+        ArgWriter {
+            buf: vec![]
+        }.short(method.0).short(method.1)
+    }
+
+    fn octet(mut self, value: u8) -> ArgWriter {
+        self.buf.push(value);
+        self
+    }
+
+    fn short(mut self, value: u16) -> ArgWriter {
+        self.buf.extend_from_slice(&[(value >> 8) as u8, value as u8]);
+        self
+    }
+
+    fn long(self, value: u32) -> ArgWriter {
+        self.short((value >> 16) as u16).short(value as u16)
+    }
+
+    fn longlong(self, value: u64) -> ArgWriter {
+        self.long((value >> 32) as u32).long(value as u32)
+    }
+
+    fn shortstr(mut self, value: &str) -> ArgWriter {
+        let len = cmp::min(value.len(), 255);
+        self.buf.push(len as u8);
+        self.buf.extend_from_slice(&value.as_bytes()[..len]);
+        self
+    }
+
+    fn longstr(mut self, value: &[u8]) -> ArgWriter {
+        self = self.long(value.len() as u32);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    fn empty_table(self) -> ArgWriter {
+        self.long(0)
+    }
+
+    /// A field table of integers, each written as a signed 32 bit value.
+    fn int_table(self, fields: &[(&str, u32)]) -> ArgWriter {
+        let mut table = ArgWriter {
+            buf: vec![]
+        };
+        for &(name, value) in fields.iter() {
+            table = table.shortstr(name).octet(b'I').long(cmp::min(value, i32::MAX as u32));
+        }
+        self.longstr(&table.buf)
+    }
+}
+
+/// Arguments of a method, or a content header, being read.
+struct ArgReader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> ArgReader<'a> {
+    fn new(data: &'a [u8]) -> ArgReader<'a> {
+        ArgReader {
+            data: data,
+            pos: 0
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], LogParserError> {
+        if self.pos + len > self.data.len() {
+            return Err(protocol_error("truncated frame"));
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+
+    fn octet(&mut self) -> Result<u8, LogParserError> {
+        Ok(try!(self.bytes(1))[0])
+    }
+
+    fn short(&mut self) -> Result<u16, LogParserError> {
+        let bytes = try!(self.bytes(2));
+        Ok((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    fn long(&mut self) -> Result<u32, LogParserError> {
+        Ok((try!(self.short()) as u32) << 16 | try!(self.short()) as u32)
+    }
+
+    fn longlong(&mut self) -> Result<u64, LogParserError> {
+        Ok((try!(self.long()) as u64) << 32 | try!(self.long()) as u64)
+    }
+
+    fn shortstr(&mut self) -> Result<String, LogParserError> {
+        let len = try!(self.octet()) as usize;
+        Ok(String::from_utf8_lossy(try!(self.bytes(len))).into_owned())
+    }
+
+    fn method(&mut self) -> Result<(u16, u16), LogParserError> {
+        Ok((try!(self.short()), try!(self.short())))
+    }
+
+    /// Read a field table, returning the fields with integer values.
+    fn int_table(&mut self) -> Result<HashMap<String, i64>, LogParserError> {
+        let len = try!(self.long()) as usize;
+        let mut fields = ArgReader::new(try!(self.bytes(len)));
+        let mut rv = HashMap::new();
+        while fields.pos < fields.data.len() {
+            let name = try!(fields.shortstr());
+            let value = match try!(fields.octet()) {
+                b'b' => Some(try!(fields.octet()) as i8 as i64),
+                b'B' => Some(try!(fields.octet()) as i64),
+                b's' => Some(try!(fields.short()) as i16 as i64),
+                b'u' => Some(try!(fields.short()) as i64),
+                b'I' => Some(try!(fields.long()) as i32 as i64),
+                b'i' => Some(try!(fields.long()) as i64),
+                b'l' => Some(try!(fields.longlong()) as i64),
+                b'V' => None,
+                b't' => {
+                    try!(fields.bytes(1));
+                    None
+                },
+                b'f' => {
+                    try!(fields.bytes(4));
+                    None
+                },
+                b'D' => {
+                    try!(fields.bytes(5));
+                    None
+                },
+                b'd' | b'L' | b'T' => {
+                    try!(fields.bytes(8));
+                    None
+                },
+                b'S' | b'x' | b'F' | b'A' => {
+                    let len = try!(fields.long()) as usize;
+                    try!(fields.bytes(len));
+                    None
+                },
+                kind => return Err(protocol_error(&*format!("unknown field type {}", kind)))
+            };
+            if let Some(value) = value {
+                rv.insert(name, value);
+            }
+        }
+        Ok(rv)
+    }
+}
+
+struct Frame {
+    kind: u8,
+    payload: Vec<u8>
+}
+
+#[derive(RustcEncodable)]
+struct DeadLetter {
+    error: String,
+    message: String
+}
+
+/// A JobSource consuming from an AMQP 0-9-1 queue. Messages are acknowledged
+/// individually; dead-lettered messages are published, with the error, to
+/// the dead-letter queue, and only acknowledged once the broker confirms
+/// the publish. Messages requeued with more attempts are published again
+/// to the queue with the attempts in a header, and the original is then
+/// acknowledged. Unacknowledged messages are returned to the queue by the
+/// broker if the connection is lost.
+pub struct AmqpSource {
+    stream: TcpStream,
+    config: AmqpConfig,
+    buf: Vec<u8>,
+    frame_max: u32,
+    // Bodies and attempts of unacknowledged messages, by delivery tag
+    pending: HashMap<u64, (Vec<u8>, u32)>,
+    // Messages delivered while waiting for a publish to be confirmed
+    delivered: VecDeque<Delivery>,
+    // Sequence number of the last message published, as used by confirms
+    published: u64
+}
+
+impl AmqpSource {
+    pub fn connect(config: AmqpConfig) -> Result<AmqpSource, LogParserError> {
+        let stream = try!(TcpStream::connect((&*config.host, config.port)));
+        try!(stream.set_read_timeout(Some(config.poll_interval)));
+        let mut source = AmqpSource {
+            stream: stream,
+            config: config,
+            buf: Vec::with_capacity(FRAME_MAX as usize),
+            frame_max: FRAME_MAX,
+            pending: HashMap::new(),
+            delivered: VecDeque::new(),
+            published: 0
+        };
+        try!(source.handshake());
+        Ok(source)
+    }
+
+    fn handshake(&mut self) -> Result<(), LogParserError> {
+        try!(self.stream.write_all(PROTOCOL_HEADER));
+        // The server properties and mechanisms don't matter; PLAIN is always supported
+        try!(self.expect_method(0, CONNECTION_START));
+        let credentials = format!("\0{}\0{}", self.config.user, self.config.password);
+        try!(self.send_method(0, ArgWriter::method(CONNECTION_START_OK)
+                              .empty_table()
+                              .shortstr("PLAIN")
+                              .longstr(credentials.as_bytes())
+                              .shortstr("en_US")));
+
+        let tune = try!(self.expect_method(0, CONNECTION_TUNE));
+        let (channel_max, frame_max) = {
+            let mut args = ArgReader::new(&tune);
+            try!(args.method());
+            (try!(args.short()), try!(args.long()))
+        };
+        if frame_max != 0 {
+            self.frame_max = cmp::min(frame_max, FRAME_MAX);
+        }
+        // Heartbeats are disabled, as the connection may sit idle while parsing
+        try!(self.send_method(0, ArgWriter::method(CONNECTION_TUNE_OK)
+                              .short(channel_max)
+                              .long(self.frame_max)
+                              .short(0)));
+        let vhost = self.config.vhost.clone();
+        try!(self.send_method(0, ArgWriter::method(CONNECTION_OPEN).shortstr(&vhost).shortstr("").octet(0)));
+        try!(self.expect_method(0, CONNECTION_OPEN_OK));
+
+        try!(self.send_method(CHANNEL, ArgWriter::method(CHANNEL_OPEN).shortstr("")));
+        try!(self.expect_method(CHANNEL, CHANNEL_OPEN_OK));
+        // Have the broker confirm each publish, so dead letters aren't lost
+        try!(self.send_method(CHANNEL, ArgWriter::method(CONFIRM_SELECT).octet(0)));
+        try!(self.expect_method(CHANNEL, CONFIRM_SELECT_OK));
+        let prefetch = self.config.prefetch;
+        try!(self.send_method(CHANNEL, ArgWriter::method(BASIC_QOS).long(0).short(prefetch).octet(0)));
+        try!(self.expect_method(CHANNEL, BASIC_QOS_OK));
+        // The job queue belongs to the producers, so only check it exists, but
+        // create the dead-letter queue so that dead letters have somewhere to go
+        let queue = self.config.queue.clone();
+        try!(self.declare_queue(&queue, true));
+        let dead_letter_queue = self.config.dead_letter_queue.clone();
+        try!(self.declare_queue(&dead_letter_queue, false));
+        try!(self.send_method(CHANNEL, ArgWriter::method(BASIC_CONSUME)
+                              .short(0)
+                              .shortstr(&queue)
+                              .shortstr("")
+                              .octet(0)
+                              .empty_table()));
+        try!(self.expect_method(CHANNEL, BASIC_CONSUME_OK));
+        Ok(())
+    }
+
+    /// Declare a durable queue, or with passive set, fail if it doesn't exist.
+    fn declare_queue(&mut self, queue: &str, passive: bool) -> Result<(), LogParserError> {
+        let flags = if passive { 1 } else { 2 };
+        try!(self.send_method(CHANNEL, ArgWriter::method(QUEUE_DECLARE)
+                              .short(0)
+                              .shortstr(queue)
+                              .octet(flags)
+                              .empty_table()));
+        try!(self.expect_method(CHANNEL, QUEUE_DECLARE_OK));
+        Ok(())
+    }
+
+    fn send_frame(&mut self, kind: u8, channel: u16, payload: &[u8]) -> Result<(), LogParserError> {
+        let header = ArgWriter {
+            buf: vec![kind]
+        }.short(channel).long(payload.len() as u32);
+        try!(self.stream.write_all(&header.buf));
+        try!(self.stream.write_all(payload));
+        try!(self.stream.write_all(&[FRAME_END]));
+        Ok(())
+    }
+
+    fn send_method(&mut self, channel: u16, args: ArgWriter) -> Result<(), LogParserError> {
+        self.send_frame(FRAME_METHOD, channel, &args.buf)
+    }
+
+    /// Read the next frame, or None if wait is false and one doesn't
+    /// arrive within the poll interval.
+    fn read_frame(&mut self, wait: bool) -> Result<Option<Frame>, LogParserError> {
+        let mut chunk = [0; 8192];
+        loop {
+            if self.buf.len() >= 7 {
+                let size = try!(ArgReader::new(&self.buf[3..7]).long()) as usize;
+                if self.buf.len() >= size + 8 {
+                    if self.buf[size + 7] != FRAME_END {
+                        return Err(protocol_error("invalid frame end"));
+                    }
+                    let frame = Frame {
+                        kind: self.buf[0],
+                        payload: self.buf[7..size + 7].to_vec()
+                    };
+                    self.buf.drain(..size + 8);
+                    if frame.kind == FRAME_HEARTBEAT {
+                        continue;
+                    }
+                    return Ok(Some(frame));
+                }
+            }
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(protocol_error("connection closed")),
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                    e.kind() == io::ErrorKind::TimedOut => {
+                    if !wait {
+                        return Ok(None);
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(LogParserError::Io(e))
+            }
+        }
+    }
+
+    fn read_frame_of_kind(&mut self, kind: u8) -> Result<Vec<u8>, LogParserError> {
+        let frame = try!(self.read_frame(true)).expect("Waiting read returned no frame");
+        if frame.kind != kind {
+            return Err(protocol_error(&*format!("expected frame type {}, got {}", kind, frame.kind)));
+        }
+        Ok(frame.payload)
+    }
+
+    fn expect_method(&mut self, channel: u16, expected: (u16, u16)) -> Result<Vec<u8>, LogParserError> {
+        let payload = try!(self.read_frame_of_kind(FRAME_METHOD));
+        let method = try!(check_close(&payload));
+        if method != expected {
+            return Err(protocol_error(&*format!("expected method {:?} on channel {}, got {:?}",
+                                                expected, channel, method)));
+        }
+        Ok(payload)
+    }
+
+    fn publish(&mut self, routing_key: &str, body: &[u8], headers: &[(&str, u32)])
+               -> Result<(), LogParserError> {
+        try!(self.send_method(CHANNEL, ArgWriter::method(BASIC_PUBLISH)
+                              .short(0)
+                              .shortstr("")
+                              .shortstr(routing_key)
+                              // Mandatory, so an unroutable message is returned
+                              .octet(1)));
+        // Content type, headers and persistent delivery mode
+        let header = ArgWriter {
+            buf: vec![]
+        }.short(BASIC_PUBLISH.0).short(0).longlong(body.len() as u64)
+            .short(0x8000 | 0x2000 | 0x1000).shortstr("application/json").int_table(headers).octet(2);
+        try!(self.send_frame(FRAME_HEADER, CHANNEL, &header.buf));
+        let max_body = self.frame_max as usize - 8;
+        for chunk in body.chunks(max_body) {
+            try!(self.send_frame(FRAME_BODY, CHANNEL, chunk));
+        }
+        self.published += 1;
+        Ok(())
+    }
+
+    /// Wait for the broker to confirm the last message published, keeping
+    /// any messages delivered in the meantime for next. A message returned
+    /// as unroutable counts as a failed publish, though the broker then
+    /// confirms it.
+    fn wait_for_confirm(&mut self) -> Result<(), LogParserError> {
+        let mut returned = None;
+        loop {
+            let payload = try!(self.read_frame_of_kind(FRAME_METHOD));
+            let method = try!(check_close(&payload));
+            if method == BASIC_DELIVER {
+                let delivery = try!(self.read_delivery(&payload));
+                self.delivered.push_back(delivery);
+                continue;
+            }
+            if method == BASIC_RETURN {
+                let (code, text) = {
+                    let mut args = ArgReader::new(&payload);
+                    try!(args.method());
+                    (try!(args.short()), try!(args.shortstr()))
+                };
+                try!(self.read_content());
+                returned = Some(format!("AMQP broker returned the publish: {} {}", code, text));
+                continue;
+            }
+            if method != BASIC_ACK && method != BASIC_NACK {
+                continue;
+            }
+            let (tag, multiple) = {
+                let mut args = ArgReader::new(&payload);
+                try!(args.method());
+                (try!(args.longlong()), try!(args.octet()) & 1 != 0)
+            };
+            // With multiple set, the confirm covers every message up to the tag
+            if tag == self.published || (multiple && tag > self.published) {
+                if method == BASIC_NACK {
+                    return Err(LogParserError::Other("AMQP broker rejected the publish".into()));
+                }
+                return match returned {
+                    Some(message) => Err(LogParserError::Other(message)),
+                    None => Ok(())
+                };
+            }
+        }
+    }
+
+    /// Read the content of a message following a basic.deliver method.
+    fn read_delivery(&mut self, deliver: &[u8]) -> Result<Delivery, LogParserError> {
+        let tag = {
+            let mut args = ArgReader::new(deliver);
+            try!(args.method());
+            try!(args.shortstr());
+            try!(args.longlong())
+        };
+
+        let (body, attempts) = try!(self.read_content());
+        self.pending.insert(tag, (body.clone(), attempts));
+        Ok(Delivery {
+            id: tag,
+            body: body,
+            attempts: attempts
+        })
+    }
+
+    /// Read the header and body following a basic.deliver or basic.return
+    /// method, returning the body and the attempts from its headers.
+    fn read_content(&mut self) -> Result<(Vec<u8>, u32), LogParserError> {
+        // The rest of the message follows without any other frames in between
+        let header = try!(self.read_frame_of_kind(FRAME_HEADER));
+        let (size, attempts) = {
+            let mut args = ArgReader::new(&header);
+            try!(args.short());
+            try!(args.short());
+            let size = try!(args.longlong()) as usize;
+            // Only the content type and encoding come before the headers
+            let flags = try!(args.short());
+            if flags & 0x8000 != 0 {
+                try!(args.shortstr());
+            }
+            if flags & 0x4000 != 0 {
+                try!(args.shortstr());
+            }
+            let attempts = if flags & 0x2000 != 0 {
+                try!(args.int_table()).get(ATTEMPTS_HEADER)
+                    .map_or(0, |&x| cmp::max(0, cmp::min(x, u32::MAX as i64)) as u32)
+            } else {
+                0
+            };
+            (size, attempts)
+        };
+        let mut body = Vec::with_capacity(size);
+        while body.len() < size {
+            body.extend_from_slice(&try!(self.read_frame_of_kind(FRAME_BODY)));
+        }
+        Ok((body, attempts))
+    }
+
+    fn take(&mut self, id: u64) -> Result<(Vec<u8>, u32), LogParserError> {
+        self.pending.remove(&id).ok_or_else(
+            || LogParserError::Other(format!("Unknown AMQP delivery {}", id)))
+    }
+}
+
+impl JobSource for AmqpSource {
+    fn next(&mut self) -> Result<Option<Delivery>, LogParserError> {
+        if let Some(delivery) = self.delivered.pop_front() {
+            return Ok(Some(delivery));
+        }
+        loop {
+            let frame = match try!(self.read_frame(false)) {
+                Some(x) => x,
+                None => return Ok(None)
+            };
+            if frame.kind != FRAME_METHOD {
+                return Err(protocol_error("content without a method"));
+            }
+            if try!(check_close(&frame.payload)) != BASIC_DELIVER {
+                continue;
+            }
+            return self.read_delivery(&frame.payload).map(Some);
+        }
+    }
+
+    fn ack(&mut self, id: u64) -> Result<(), LogParserError> {
+        try!(self.take(id));
+        self.send_method(CHANNEL, ArgWriter::method(BASIC_ACK).longlong(id).octet(0))
+    }
+
+    fn requeue(&mut self, id: u64, attempts: u32) -> Result<(), LogParserError> {
+        let (body, delivered_attempts) = try!(self.take(id));
+        if attempts == delivered_attempts {
+            return self.send_method(CHANNEL, ArgWriter::method(BASIC_REJECT).longlong(id).octet(1));
+        }
+        // The headers of a rejected message can't be changed, so publish a copy
+        let queue = self.config.queue.clone();
+        try!(self.publish(&queue, &body, &[(ATTEMPTS_HEADER, attempts)]));
+        try!(self.wait_for_confirm());
+        self.send_method(CHANNEL, ArgWriter::method(BASIC_ACK).longlong(id).octet(0))
+    }
+
+    fn dead_letter(&mut self, id: u64, error: &LogParserError) -> Result<(), LogParserError> {
+        let (body, _) = try!(self.take(id));
+        let dead_letter = json::encode(&DeadLetter {
+            error: format!("{}: {}", error.name(), error.description()),
+            message: String::from_utf8_lossy(&body).into_owned()
+        }).unwrap();
+        let queue = self.config.dead_letter_queue.clone();
+        try!(self.publish(&queue, dead_letter.as_bytes(), &[]));
+        // If the publish fails the message is left unacknowledged, so the
+        // broker redelivers it once the connection closes
+        try!(self.wait_for_confirm());
+        self.send_method(CHANNEL, ArgWriter::method(BASIC_ACK).longlong(id).octet(0))
+    }
+}
@@ -0,0 +1,87 @@
+extern crate logparser;
+
+use logparser::amqp::{AmqpConfig, AmqpSource};
+use logparser::daemon::{run_daemon, DaemonOptions, DirectorySink, JobSource, ResultSink,
+                        SpoolSource, StdoutSink};
+use logparser::limits::CancelHandle;
+use logparser::logparser::LogParserError;
+use std::env;
+use std::process;
+
+static PASSWORD_ENV_VAR: &'static str = "LOGPARSER_AMQP_PASSWORD";
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} spool SPOOL_DIR OUTPUT [WORKERS]", program);
+    println!("       {} amqp [--port PORT] [--user USER] [--password PASSWORD] [--vhost VHOST] \
+              HOST QUEUE DEAD_LETTER_QUEUE OUTPUT [WORKERS]", program);
+    println!("OUTPUT is a directory for the results, or - to write them to stdout");
+    println!("The AMQP password can also be given in the {} environment variable", PASSWORD_ENV_VAR);
+    process::exit(2);
+}
+
+/// Read the AMQP connection options and positional arguments after `amqp`.
+fn amqp_config(program: &str, args: &[String]) -> (AmqpConfig, usize) {
+    let mut pos = 0;
+    let mut port = None;
+    let mut user = None;
+    let mut password = env::var(PASSWORD_ENV_VAR).ok();
+    let mut vhost = None;
+    while args.get(pos).map(|x| x.starts_with("--")).unwrap_or(false) {
+        let value = match args.get(pos + 1) {
+            Some(x) => x.clone(),
+            None => usage(program)
+        };
+        match &*args[pos] {
+            "--port" => port = Some(match value.parse::<u16>() {
+                Ok(x) => x,
+                Err(_) => usage(program)
+            }),
+            "--user" => user = Some(value),
+            "--password" => password = Some(value),
+            "--vhost" => vhost = Some(value),
+            _ => usage(program)
+        }
+        pos += 2;
+    }
+    if args.len() < pos + 4 {
+        usage(program);
+    }
+    let mut config = AmqpConfig::new(&args[pos], &args[pos + 1], &args[pos + 2]);
+    config.port = port.unwrap_or(config.port);
+    config.user = user.unwrap_or(config.user);
+    config.password = password.unwrap_or(config.password);
+    config.vhost = vhost.unwrap_or(config.vhost);
+    (config, pos + 3)
+}
+
+fn run(args: &[String]) -> Result<(), LogParserError> {
+    let (mut source, rest): (Box<JobSource>, &[String]) = match args.get(1).map(|x| &**x) {
+        Some("spool") if args.len() >= 4 => (Box::new(try!(SpoolSource::open(&*args[2]))), &args[3..]),
+        Some("amqp") => {
+            let (config, used) = amqp_config(&args[0], &args[2..]);
+            (Box::new(try!(AmqpSource::connect(config))), &args[2 + used..])
+        },
+        _ => usage(&args[0])
+    };
+    let mut sink: Box<ResultSink> = match &*rest[0] {
+        "-" => Box::new(StdoutSink),
+        dir => Box::new(try!(DirectorySink::new(dir)))
+    };
+    let mut options = DaemonOptions::new();
+    if let Some(workers) = rest.get(1) {
+        options.workers = match workers.parse() {
+            Ok(x) if x > 0 => x,
+            _ => usage(&args[0])
+        };
+    }
+    // Runs until the process is killed; unacknowledged jobs are redelivered
+    run_daemon(&mut *source, &mut *sink, &options, &CancelHandle::new())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if let Err(e) = run(&args) {
+        println!("Daemon failed: {}", e);
+        process::exit(1);
+    }
+}
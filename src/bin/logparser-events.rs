@@ -0,0 +1,48 @@
+extern crate logparser;
+
+use logparser::events::{follow_log_events, parse_log_events};
+use logparser::follow::FollowOptions;
+use logparser::ParseOptions;
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} [--follow] SOURCE [PARSER...]", program);
+    println!("Writes each event found while parsing the log to stdout, one JSON object per line");
+    println!("With --follow, SOURCE is a live log URL or a local file that is still being written");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let follow = args.get(1).map(|x| x == "--follow").unwrap_or(false);
+    let rest = if follow { &args[2..] } else { &args[1..] };
+    if rest.len() < 1 {
+        usage(&args[0]);
+    }
+    let mut options = ParseOptions::new();
+    if rest.len() > 1 {
+        options.parsers = Some(rest[1..].to_vec());
+    }
+    let user_agent = "Log Parser Events";
+    let mut events = if follow {
+        follow_log_events(&rest[0], user_agent, options, FollowOptions::new())
+    } else {
+        parse_log_events(&rest[0], user_agent, options)
+    };
+    {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        for event in events.by_ref() {
+            // Flush each line so consumers see events as soon as they're found
+            if writeln!(stdout, "{}", event.to_json()).and_then(|_| stdout.flush()).is_err() {
+                break;
+            }
+        }
+    }
+    if let Err(e) = events.finish() {
+        let _ = writeln!(io::stderr(), "Parse failed: {}", e);
+        process::exit(1);
+    }
+}
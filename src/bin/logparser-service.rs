@@ -0,0 +1,43 @@
+extern crate logparser;
+
+use logparser::service::{serve, ServiceOptions, RESERVED_THREADS};
+use std::env;
+use std::process;
+
+static ALLOWED_HOSTS_ENV_VAR: &'static str = "LOGPARSER_ALLOWED_HOSTS";
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 3 {
+        println!("Usage: {} [ADDRESS] [MAX_CONCURRENT]", args[0]);
+        println!("Logs are fetched by URL only from the comma-separated hosts in the {} \
+                  environment variable, or the log archives if it isn't set", ALLOWED_HOSTS_ENV_VAR);
+        process::exit(2);
+    }
+    let addr = args.get(1).map(|x| &**x).unwrap_or("127.0.0.1:8080");
+    let mut options = ServiceOptions::new();
+    if let Some(max_concurrent) = args.get(2) {
+        options.max_concurrent = match max_concurrent.parse() {
+            Ok(x) if x > 0 => x,
+            _ => {
+                println!("Invalid MAX_CONCURRENT {}", max_concurrent);
+                process::exit(2);
+            }
+        };
+        options.threads = options.max_concurrent + RESERVED_THREADS;
+    }
+    if let Ok(hosts) = env::var(ALLOWED_HOSTS_ENV_VAR) {
+        options.allowed_hosts = hosts.split(',')
+            .map(|x| x.trim().to_lowercase())
+            .filter(|x| x.len() > 0)
+            .collect();
+    }
+    match serve(addr, options) {
+        // Serves until the process is killed
+        Ok(_listening) => println!("Listening on {}", addr),
+        Err(e) => {
+            println!("Failed to start service: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use classifier::Classifier;
+use http::Validators;
+use logparser::{LogParser, LogParserError};
+use normalize::{hash_str, u32_to_le_bytes, FnvHasher, Normalizer};
+use rustc_serialize::json;
+use std::env;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use time;
+
+pub static CACHE_DIR_ENV_VAR: &'static str = "LOGPARSER_CACHE_DIR";
+
+/// Version of the artifacts. This must be increased whenever a change to the
+/// parsers or their built-in patterns changes the artifacts for a log, so
+/// that results cached by earlier builds aren't reused.
+pub static ARTIFACT_VERSION: u32 = 4;
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct CacheEntry {
+    url: String,
+    version: String,
+    validators: Validators,
+    artifacts: Vec<(String, String)>
+}
+
+/// Artifacts from an earlier parse, along with the validators of the
+/// response they were parsed from.
+pub struct CachedResult {
+    pub validators: Validators,
+    pub artifacts: Vec<(&'static str, String)>
+}
+
+/// A directory of parse results, with one file per log URL and version of
+/// the parsers. Results are only reused if the server confirms the log
+/// hasn't changed, so only results with validators are stored.
+#[derive(Clone)]
+pub struct ResultCache {
+    dir: PathBuf
+}
+
+impl ResultCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> ResultCache {
+        ResultCache {
+            dir: dir.into()
+        }
+    }
+
+    /// Create a cache in the directory named in the LOGPARSER_CACHE_DIR
+    /// environment variable, if it is set.
+    pub fn from_env() -> Option<ResultCache> {
+        env::var(CACHE_DIR_ENV_VAR).ok().map(ResultCache::new)
+    }
+
+    fn path(&self, url: &str, version: &str) -> PathBuf {
+        let mut hasher = FnvHasher::new();
+        hash_str(url, &mut hasher);
+        hash_str(version, &mut hasher);
+        self.dir.join(format!("{:016x}.json", hasher.finish()))
+    }
+
+    /// Look up the result of parsing url with the given version of the
+    /// parsers. names are the artifact names that may be returned. Any
+    /// problem reading the cache is treated as a miss.
+    pub fn get(&self, url: &str, version: &str, names: &[&'static str]) -> Option<CachedResult> {
+        let mut data = String::new();
+        if File::open(self.path(url, version))
+            .and_then(|mut x| x.read_to_string(&mut data)).is_err() {
+            return None;
+        }
+        let entry: CacheEntry = match json::decode(&data) {
+            Ok(x) => x,
+            Err(_) => return None
+        };
+        // Guard against hash collisions
+        if entry.url != url || entry.version != version {
+            return None;
+        }
+        let mut artifacts = Vec::with_capacity(entry.artifacts.len());
+        for (name, artifact) in entry.artifacts.into_iter() {
+            match names.iter().find(|x| **x == name) {
+                Some(name) => artifacts.push((*name, artifact)),
+                None => return None
+            }
+        }
+        Some(CachedResult {
+            validators: entry.validators,
+            artifacts: artifacts
+        })
+    }
+
+    /// Store the result of parsing url, replacing any earlier result.
+    pub fn put(&self, url: &str, version: &str, validators: &Validators,
+               artifacts: &[(&'static str, String)]) -> Result<(), LogParserError> {
+        if validators.is_empty() {
+            return Ok(());
+        }
+        let entry = CacheEntry {
+            url: url.into(),
+            version: version.into(),
+            validators: validators.clone(),
+            artifacts: artifacts.iter().map(|&(name, ref artifact)| (name.into(), artifact.clone())).collect()
+        };
+        let data = try!(json::encode(&entry).map_err(
+            |e| LogParserError::Other(format!("Failed to encode cache entry: {}", e))));
+
+        // Write to a temporary file first so readers never see a partial entry
+        try!(fs::create_dir_all(&self.dir));
+        let path = self.path(url, version);
+        let temp_path = path.with_extension(format!("{}.tmp", time::precise_time_ns()));
+        try!(try!(File::create(&temp_path)).write_all(data.as_bytes()));
+        try!(fs::rename(&temp_path, &path));
+        Ok(())
+    }
+}
+
+/// Identify the version of the parsers, classifier rules and normalization
+/// rules, so that results from other versions aren't reused. always_step_data
+/// is ParseOptions::always_step_data, which changes the artifacts returned.
+pub fn parser_version(parsers: &[Box<LogParser>], classifier: &Classifier,
+                      normalizer: &Normalizer, always_step_data: bool) -> String {
+    // Only explicit bytes are hashed, so the version is the same on every platform
+    let mut hasher = FnvHasher::new();
+    hash_str(env!("CARGO_PKG_VERSION"), &mut hasher);
+    hasher.write(&u32_to_le_bytes(ARTIFACT_VERSION));
+    hasher.write(&u32_to_le_bytes(parsers.len() as u32));
+    for parser in parsers.iter() {
+        hash_str(parser.name(), &mut hasher);
+    }
+    classifier.hash_rules(&mut hasher);
+    normalizer.hash_rules(&mut hasher);
+    hasher.write(&[always_step_data as u8]);
+    format!("{:016x}", hasher.finish())
+}
@@ -0,0 +1,287 @@
+use cache::parser_version;
+use classifier::Classifier;
+use http::{get, read};
+use limits::{LimitedReader, LimitState};
+use lines::LineReader;
+use logparser::{LogParser, LogParserError};
+use metadata::Failure;
+use metrics::{ParseStats, TimedReader};
+use prefilter::{ParserSet, Prefilter};
+use rustc_serialize::json;
+use rustc_serialize::Decodable;
+use std::io::{self, BufRead, Read};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use {classify_and_describe, finish_all, is_complete, parse_line, Finished, ParseOptions};
+
+/// Decode the state saved by a parser's checkpoint.
+pub fn decode_state<T: Decodable>(parser: &str, state: &str) -> Result<T, LogParserError> {
+    json::decode(state).map_err(
+        |e| LogParserError::Other(format!("Invalid checkpoint for {}: {}", parser, e)))
+}
+
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct ParserCheckpoint {
+    pub name: String,
+    pub state: String,
+    /// The line number and error, if the parser failed in a best-effort parse
+    pub failure: Option<(u32, String)>
+}
+
+/// The state of a parse part way through a log, from which it can be
+/// carried on later, possibly in another process.
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct Checkpoint {
+    /// The version of the parsers, as used by the result cache. Only the
+    /// same version can carry on from the checkpoint.
+    pub version: String,
+    /// Number of the next line to parse
+    pub line_number: u32,
+    /// Offset of the start of the next line, in decompressed bytes
+    pub offset: u64,
+    /// Number of the last line that was parsed
+    pub last_line_number: u32,
+    pub parsers: Vec<ParserCheckpoint>
+}
+
+impl Checkpoint {
+    pub fn to_json(&self) -> String {
+        json::encode(self).unwrap()
+    }
+
+    pub fn from_json(data: &str) -> Result<Checkpoint, LogParserError> {
+        json::decode(data).map_err(|e| LogParserError::Other(format!("Invalid checkpoint: {}", e)))
+    }
+}
+
+/// A parse that can be run over a log in several parts, checkpointing in
+/// between. The final artifacts are the same as from parse_reader.
+pub struct ResumableParse {
+    parsers: Vec<Box<LogParser>>,
+    version: String,
+    best_effort: bool,
+    prefilter: Prefilter,
+    failures: Vec<Failure>,
+    failed: ParserSet,
+    line_number: u32,
+    offset: u64,
+    last_line_number: u32
+}
+
+impl ResumableParse {
+    pub fn new(parsers: Vec<Box<LogParser>>, version: String, best_effort: bool) -> ResumableParse {
+        ResumableParse {
+            prefilter: Prefilter::new(&parsers),
+            parsers: parsers,
+            version: version,
+            best_effort: best_effort,
+            failures: vec![],
+            failed: 0,
+            line_number: 0,
+            offset: 0,
+            last_line_number: 0
+        }
+    }
+
+    /// Carry on a parse from a checkpoint, with a fresh set of the same parsers.
+    pub fn resume(parsers: Vec<Box<LogParser>>, version: String, best_effort: bool,
+                  checkpoint: &Checkpoint) -> Result<ResumableParse, LogParserError> {
+        if checkpoint.version != version || checkpoint.parsers.len() != parsers.len() {
+            return Err(LogParserError::Other("Checkpoint is for different parsers".into()));
+        }
+        let mut parse = ResumableParse::new(parsers, version, best_effort);
+        for (idx, (parser, saved)) in parse.parsers.iter_mut().zip(checkpoint.parsers.iter()).enumerate() {
+            if parser.name() != saved.name {
+                return Err(LogParserError::Other("Checkpoint is for different parsers".into()));
+            }
+            try!(parser.restore(&saved.state));
+            if let Some((line_number, ref error)) = saved.failure {
+                parse.failures.push(Failure {
+                    source: parser.name(),
+                    linenumber: line_number,
+                    error: error.clone()
+                });
+                parse.failed |= 1 << idx;
+            }
+        }
+        // Failures are listed in the order they happened
+        parse.failures.sort_by_key(|x| x.linenumber);
+        parse.line_number = checkpoint.line_number;
+        parse.offset = checkpoint.offset;
+        parse.last_line_number = checkpoint.last_line_number;
+        Ok(parse)
+    }
+
+    /// Offset of the start of the next line to parse, in decompressed bytes.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Run the parsers over the log from a reader starting at the offset of
+    /// the next line. Returns true if the parse is finished, or false if
+    /// one of the limits stopped it, or, when the log may still grow (not
+    /// at_end), the reader ran out. A final line without a newline is left
+    /// for the next run unless at_end. The line and byte limits, like the
+    /// progress recorded in the limits, count from where this run starts.
+    pub fn run<R: BufRead>(&mut self, reader: R, limits: &LimitState, at_end: bool)
+                           -> Result<bool, LogParserError> {
+        let (start_line, start_offset) = (self.line_number, self.offset);
+        let mut lines = LineReader::starting_at(reader, start_line, start_offset);
+        lines.set_complete_lines_only(!at_end);
+        let rv = self.run_lines(&mut lines, limits, at_end, start_line, start_offset);
+        limits.set_progress(lines.lines_read() - start_line, lines.bytes_read() - start_offset);
+        rv
+    }
+
+    fn run_lines<R: BufRead>(&mut self, lines: &mut LineReader<R>, limits: &LimitState, at_end: bool,
+                             start_line: u32, start_offset: u64) -> Result<bool, LogParserError> {
+        loop {
+            let (line_number, offset, line) = match lines.next_line() {
+                Ok(Some(x)) => x,
+                Ok(None) => return Ok(at_end),
+                // The read was stopped by the limits, rather than failing
+                Err(_) if limits.stop_reason().is_some() => return Ok(false),
+                Err(e) => {
+                    if !self.best_effort {
+                        return Err(e);
+                    }
+                    self.failures.push(Failure::new("log_reader", self.last_line_number, &e));
+                    return Ok(true);
+                }
+            };
+            if limits.check_line(line_number - start_line, offset - start_offset).is_some() {
+                return Ok(false);
+            }
+            let matches = self.prefilter.matches(line);
+            for (idx, parser) in self.parsers.iter_mut().enumerate() {
+                let parser_bit = 1 << idx;
+                if self.failed & parser_bit != 0 {
+                    continue;
+                }
+                if let Err(e) = parse_line(&mut **parser, line, line_number, offset,
+                                           matches & parser_bit != 0) {
+                    if !self.best_effort {
+                        return Err(e);
+                    }
+                    self.failures.push(Failure::new(parser.name(), line_number, &e));
+                    self.failed |= parser_bit;
+                }
+            }
+            self.last_line_number = line_number;
+            self.line_number = lines.lines_read();
+            self.offset = lines.bytes_read();
+            let failed = self.failed;
+            if self.parsers.iter().enumerate().all(
+                |(idx, x)| failed & (1 << idx) != 0 || is_complete(&**x, line_number + 1, self.offset)) {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Save the state of the parse, to carry on from later.
+    pub fn checkpoint(&self) -> Result<Checkpoint, LogParserError> {
+        let mut parsers = Vec::with_capacity(self.parsers.len());
+        for parser in self.parsers.iter() {
+            let state = try!(parser.checkpoint().ok_or_else(|| LogParserError::Other(
+                format!("Parser {} can't be checkpointed", parser.name()))));
+            parsers.push(ParserCheckpoint {
+                name: parser.name().into(),
+                state: state,
+                failure: self.failures.iter().find(|x| x.source == parser.name())
+                    .map(|x| (x.linenumber, x.error.clone()))
+            });
+        }
+        Ok(Checkpoint {
+            version: self.version.clone(),
+            line_number: self.line_number,
+            offset: self.offset,
+            last_line_number: self.last_line_number,
+            parsers: parsers
+        })
+    }
+
+    /// Finish the parse, returning the artifacts and any failures.
+    pub fn finish(self) -> (Vec<(&'static str, String)>, Vec<Failure>) {
+        let (finished, failures) = self.finish_parsers();
+        (finished.artifacts, failures)
+    }
+
+    /// Like finish, but with everything finish_all gives, for classifying
+    /// and describing the parse.
+    fn finish_parsers(mut self) -> (Finished, Vec<Failure>) {
+        (finish_all(&mut self.parsers, self.last_line_number), self.failures)
+    }
+}
+
+pub enum CheckpointedParse {
+    Complete(Vec<(&'static str, String)>),
+    /// One of the limits stopped the parse; it can be carried on from here
+    Stopped(Checkpoint)
+}
+
+/// Parse a log, carrying on from a checkpoint if one is given. If one of
+/// the limits stops the parse, a checkpoint is returned rather than the
+/// artifacts. max_lines and max_bytes count from the checkpoint, so each
+/// call parses at most that much more of the log. A gzipped log is
+/// downloaded and decompressed again from the start, and the part before
+/// the checkpoint skipped.
+pub fn parse_log_checkpointed(url: &str, user_agent: &str, options: &ParseOptions,
+                              checkpoint: Option<&Checkpoint>)
+                              -> Result<CheckpointedParse, LogParserError> {
+    let mut stats = options.parse_stats();
+    let result = match fetch_and_resume(url, user_agent, options, checkpoint, &mut stats) {
+        // Only finished parses are added to the metrics
+        Ok(CheckpointedParse::Stopped(checkpoint)) => return Ok(CheckpointedParse::Stopped(checkpoint)),
+        Ok(CheckpointedParse::Complete(artifacts)) => Ok(artifacts),
+        Err(e) => Err(e)
+    };
+    stats.finish(&result);
+    result.map(CheckpointedParse::Complete)
+}
+
+fn fetch_and_resume(url: &str, user_agent: &str, options: &ParseOptions,
+                    checkpoint: Option<&Checkpoint>, stats: &mut ParseStats)
+                    -> Result<CheckpointedParse, LogParserError> {
+    let classifier = try!(Classifier::from_env());
+    let limits = Arc::new(LimitState::new(&options.limits));
+    let normalizer = try!(options.normalizer());
+    let parsers = try!(options.create_parsers(&normalizer));
+    let version = parser_version(&parsers, &classifier, &normalizer, options.always_step_data);
+    let mut parse = match checkpoint {
+        Some(checkpoint) => try!(ResumableParse::resume(parsers, version, options.best_effort, checkpoint)),
+        None => ResumableParse::new(parsers, version, options.best_effort)
+    };
+
+    let request_start = Instant::now();
+    let download = try!(get(url, user_agent, Some(Duration::new(30, 0)), &options.retry_policy,
+                            limits.clone()));
+    stats.request_time = request_start.elapsed();
+    let download_stats = download.stats();
+    let download = TimedReader::new(LimitedReader::new(download, limits.clone()),
+                                    stats.download_reads.clone());
+    let mut reader = try!(read(download, options.tolerant_gzip));
+    let truncation = reader.get_ref().truncation();
+    match io::copy(&mut (&mut reader).take(parse.offset()), &mut io::sink()) {
+        Ok(skipped) if skipped == parse.offset() => {},
+        Ok(_) => return Err(LogParserError::Other("Log is shorter than the checkpoint".into())),
+        Err(_) if limits.stop_reason().is_some() => {
+            return Ok(CheckpointedParse::Stopped(try!(parse.checkpoint())));
+        },
+        Err(e) => return Err(e.into())
+    }
+
+    if !try!(parse.run(reader, &limits, true)) {
+        return Ok(CheckpointedParse::Stopped(try!(parse.checkpoint())));
+    }
+    let (lines, bytes) = limits.progress();
+    stats.lines = lines as u64;
+    stats.bytes = bytes;
+    let (finished, failures) = parse.finish_parsers();
+    let (mut rv, mut metadata) = try!(classify_and_describe(finished, failures, &classifier,
+                                                            &limits, Some(&*truncation), stats));
+    metadata.http_retries = download_stats.retries();
+    metadata.http_resumes = download_stats.resumes();
+    metadata.timings = stats.trace.as_ref().map(|x| x.spans());
+    rv.push((metadata.name(), metadata.get_artifact()));
+    Ok(CheckpointedParse::Complete(rv))
+}
@@ -0,0 +1,278 @@
+use logparser::LogParserError;
+use normalize::{hash_str, u32_to_le_bytes};
+use regex::Regex;
+use rustc_serialize::json::{self, Json};
+use rustc_serialize::{Encodable, Encoder};
+use std::env;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::Read;
+
+static MAX_EVIDENCE_LINES: usize = 20;
+
+/// Environment variable naming a JSON file of additional classification rules.
+pub static RULES_ENV_VAR: &'static str = "LOGPARSER_CLASSIFIER_RULES";
+
+lazy_static! {
+    static ref DEFAULT_RULES: Vec<RuleSpec> = vec![
+        RuleSpec::pattern("infrastructure", r"wget: unable ", 1.0),
+        RuleSpec::pattern("infrastructure", r"Remote Device Error:", 1.0),
+        RuleSpec::pattern("infrastructure", r"command timed out:", 0.8),
+        RuleSpec::pattern("infrastructure", r"Automation Error:", 0.8),
+        RuleSpec::pattern("infrastructure", r"(?i)no space left on device|disk full", 1.0),
+        RuleSpec::pattern("infrastructure", r"(?i)connection reset by peer|connection refused|network is unreachable|temporary failure in name resolution", 0.8),
+        RuleSpec::pattern("infrastructure", r"\[taskcluster\] Error:", 0.8),
+        RuleSpec::pattern("infrastructure", r"remoteFailed:", 0.8),
+        RuleSpec::pattern("product", r"TEST-UNEXPECTED-", 1.0),
+        RuleSpec::pattern("product", r"PROCESS-CRASH", 1.0),
+        RuleSpec::pattern("product", r"Assertion fail(?:ure|ed):", 1.0),
+        RuleSpec::pattern("product", r"###!!! ABORT:", 1.0),
+        RuleSpec::pattern("product", r"SUMMARY: (?:Address|Leak)Sanitizer", 1.0),
+        RuleSpec::pattern("product", r":\d+: error:|fatal error", 0.8),
+        RuleSpec::step_result("infrastructure", "exception", 0.5),
+        RuleSpec::step_result("infrastructure", "retry", 0.5),
+        RuleSpec::step_result("product", "testfailed", 0.5),
+    ];
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Category {
+    Infrastructure,
+    Product,
+    Unknown
+}
+
+impl Category {
+    fn from_str(data: &str) -> Option<Category> {
+        match data {
+            "infrastructure" => Some(Category::Infrastructure),
+            "product" => Some(Category::Product),
+            _ => None
+        }
+    }
+}
+
+impl Encodable for Category {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(match *self {
+            Category::Infrastructure => "infrastructure",
+            Category::Product => "product",
+            Category::Unknown => "unknown"
+        })
+    }
+}
+
+/// Serialized form of a rule. Each rule has either a pattern, matched against
+/// error lines, or a step_result, matched against the result of each step.
+#[derive(RustcDecodable, Clone)]
+struct RuleSpec {
+    category: String,
+    pattern: Option<String>,
+    step_result: Option<String>,
+    weight: f64
+}
+
+impl RuleSpec {
+    fn pattern(category: &str, pattern: &str, weight: f64) -> RuleSpec {
+        RuleSpec {
+            category: category.into(),
+            pattern: Some(pattern.into()),
+            step_result: None,
+            weight: weight
+        }
+    }
+
+    fn step_result(category: &str, step_result: &str, weight: f64) -> RuleSpec {
+        RuleSpec {
+            category: category.into(),
+            pattern: None,
+            step_result: Some(step_result.into()),
+            weight: weight
+        }
+    }
+}
+
+enum Matcher {
+    Line(Regex),
+    StepResult(String)
+}
+
+struct Rule {
+    category: Category,
+    matcher: Matcher,
+    weight: f64
+}
+
+impl Rule {
+    fn from_spec(spec: &RuleSpec) -> Result<Rule, LogParserError> {
+        let category = try!(Category::from_str(&spec.category).ok_or_else(
+            || LogParserError::Other(format!("Unknown rule category {}", spec.category))));
+        let matcher = match (spec.pattern.as_ref(), spec.step_result.as_ref()) {
+            (Some(pattern), None) => Matcher::Line(try!(Regex::new(pattern).map_err(
+                |e| LogParserError::Other(format!("Invalid rule pattern {}: {}", pattern, e))))),
+            (None, Some(result)) => Matcher::StepResult(result.clone()),
+            _ => return Err(LogParserError::Other(
+                "Rules must have exactly one of pattern or step_result".into()))
+        };
+        Ok(Rule {
+            category: category,
+            matcher: matcher,
+            weight: spec.weight
+        })
+    }
+}
+
+#[derive(RustcEncodable)]
+struct Evidence {
+    linenumber: u32,
+    line: String,
+    category: Category
+}
+
+#[derive(RustcEncodable)]
+struct Verdict {
+    category: Category,
+    confidence: f64,
+    evidence: Vec<Evidence>
+}
+
+pub struct Classifier {
+    rules: Vec<Rule>,
+    // The JSON of each set of added rules, to identify them in cache keys
+    added_rules: Vec<String>
+}
+
+impl Classifier {
+    pub fn new() -> Classifier {
+        Classifier {
+            rules: DEFAULT_RULES.iter()
+                .map(|x| Rule::from_spec(x).expect("Invalid default rule"))
+                .collect(),
+            added_rules: vec![]
+        }
+    }
+
+    /// Create a classifier with the default rules, plus any rules from the
+    /// file named in the LOGPARSER_CLASSIFIER_RULES environment variable.
+    pub fn from_env() -> Result<Classifier, LogParserError> {
+        let mut classifier = Classifier::new();
+        if let Ok(path) = env::var(RULES_ENV_VAR) {
+            let mut data = String::new();
+            try!(try!(File::open(path)).read_to_string(&mut data));
+            try!(classifier.add_rules(&data));
+        }
+        Ok(classifier)
+    }
+
+    /// Add rules from a JSON list of objects with `category`, `weight` and
+    /// either `pattern` or `step_result` keys. Added rules take precedence
+    /// over existing ones.
+    pub fn add_rules(&mut self, data: &str) -> Result<(), LogParserError> {
+        let specs: Vec<RuleSpec> = try!(json::decode(data).map_err(
+            |e| LogParserError::Other(format!("Invalid classifier rules: {}", e))));
+        let mut rules = Vec::with_capacity(specs.len() + self.rules.len());
+        for spec in specs.iter() {
+            rules.push(try!(Rule::from_spec(spec)));
+        }
+        rules.extend(self.rules.drain(..));
+        self.rules = rules;
+        self.added_rules.push(data.into());
+        Ok(())
+    }
+
+    /// Hash the rules, including the defaults, so that results can be
+    /// cached for a particular set of rules.
+    pub fn hash_rules<H: Hasher>(&self, state: &mut H) {
+        for spec in DEFAULT_RULES.iter() {
+            hash_str(&spec.category, state);
+            for value in [&spec.pattern, &spec.step_result].iter() {
+                match **value {
+                    Some(ref x) => {
+                        state.write(&[1]);
+                        hash_str(x, state);
+                    },
+                    None => state.write(&[0])
+                }
+            }
+            // f64 isn't Hash
+            hash_str(&spec.weight.to_string(), state);
+        }
+        state.write(&u32_to_le_bytes(self.added_rules.len() as u32));
+        for rules in self.added_rules.iter() {
+            hash_str(rules, state);
+        }
+    }
+
+    fn classify_line(&self, line: &str) -> Option<&Rule> {
+        self.rules.iter().find(|rule| match rule.matcher {
+            Matcher::Line(ref regex) => regex.is_match(line),
+            _ => false
+        })
+    }
+
+    fn classify_result(&self, result: &str) -> Option<&Rule> {
+        self.rules.iter().find(|rule| match rule.matcher {
+            Matcher::StepResult(ref x) => x == result,
+            _ => false
+        })
+    }
+
+    /// Classify a job as failing due to infrastructure or product issues,
+    /// based on the errors and step results in a step_data artifact.
+    pub fn classify(&self, step_data: Option<&str>) -> Result<String, LogParserError> {
+        let mut infra_score = 0.0;
+        let mut product_score = 0.0;
+        let mut evidence = vec![];
+
+        let data = match step_data {
+            Some(x) => try!(Json::from_str(x)),
+            None => Json::Object(json::Object::new())
+        };
+        let empty = vec![];
+        let errors = data.find("all_errors").and_then(|x| x.as_array()).unwrap_or(&empty);
+        let steps = data.find("steps").and_then(|x| x.as_array()).unwrap_or(&empty);
+
+        let line_matches = errors.iter().filter_map(|error| {
+            let line = error.find("line").and_then(|x| x.as_string()).unwrap_or("");
+            let line_number = error.find("linenumber").and_then(|x| x.as_u64()).unwrap_or(0);
+            self.classify_line(line).map(|rule| (rule, line_number as u32, line.to_owned()))
+        });
+        let result_matches = steps.iter().filter_map(|step| {
+            let name = step.find("name").and_then(|x| x.as_string()).unwrap_or("");
+            let result = step.find("result").and_then(|x| x.as_string()).unwrap_or("unknown");
+            let line_number = step.find("finished_linenumber").and_then(|x| x.as_u64()).unwrap_or(0);
+            self.classify_result(result)
+                .map(|rule| (rule, line_number as u32, format!("{}: {}", name, result)))
+        });
+
+        for (rule, line_number, line) in line_matches.chain(result_matches) {
+            match rule.category {
+                Category::Infrastructure => infra_score += rule.weight,
+                Category::Product => product_score += rule.weight,
+                Category::Unknown => {}
+            }
+            evidence.push(Evidence {
+                linenumber: line_number,
+                line: line,
+                category: rule.category
+            });
+        }
+
+        let total = infra_score + product_score;
+        let (category, confidence) = if total == 0.0 {
+            (Category::Unknown, 0.0)
+        } else if infra_score > product_score {
+            (Category::Infrastructure, infra_score / total)
+        } else {
+            (Category::Product, product_score / total)
+        };
+        evidence.retain(|x| x.category == category);
+        evidence.truncate(MAX_EVIDENCE_LINES);
+
+        Ok(json::encode(&Verdict {
+            category: category,
+            confidence: confidence,
+            evidence: evidence
+        }).unwrap())
+    }
+}
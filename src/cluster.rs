@@ -0,0 +1,227 @@
+use diff::step_data;
+use logparser::LogParserError;
+use normalize::{fingerprint, normalize_line};
+use rustc_serialize::json::Json;
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use ParseOptions;
+
+pub struct ClusterOptions {
+    /// Number of logs to download and parse at once
+    pub workers: usize,
+    /// How alike two normalized lines must be to share a cluster, as the
+    /// proportion of their distinct words in common. 1.0 only clusters
+    /// lines that normalize to the same text.
+    pub similarity: f64
+}
+
+impl ClusterOptions {
+    pub fn new() -> ClusterOptions {
+        ClusterOptions {
+            workers: 4,
+            similarity: 0.8
+        }
+    }
+}
+
+/// An error line from one of the logs.
+#[derive(RustcEncodable)]
+pub struct ClusterMember {
+    pub log: String,
+    pub step: String,
+    pub linenumber: u32,
+    pub line: String
+}
+
+/// Error lines from across the logs that appear to be the same failure.
+#[derive(RustcEncodable)]
+pub struct Cluster {
+    /// Fingerprint of the normalized representative line
+    pub fingerprint: String,
+    pub normalized: String,
+    /// The first line of the cluster, in the order the logs were given
+    pub representative: String,
+    /// The logs with a line in the cluster, in the order they were given
+    pub logs: Vec<String>,
+    pub members: Vec<ClusterMember>
+}
+
+/// A log that couldn't be parsed, so isn't in any cluster.
+#[derive(RustcEncodable)]
+pub struct LogFailure {
+    pub log: String,
+    pub error: String
+}
+
+#[derive(RustcEncodable)]
+pub struct ClusterReport {
+    /// Clusters found in the most logs first
+    pub clusters: Vec<Cluster>,
+    pub failures: Vec<LogFailure>
+}
+
+struct ClusterBuilder {
+    cluster: Cluster,
+    words: BTreeSet<String>
+}
+
+fn words(normalized: &str) -> BTreeSet<String> {
+    normalized.split_whitespace().map(|x| x.into()).collect()
+}
+
+fn similarity(a: &BTreeSet<String>, b: &BTreeSet<String>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
+
+/// Read the step name, line number, line and normalized line of each error
+/// in a step_data artifact.
+fn read_errors(step_data: &str) -> Result<Vec<(String, u32, String, String)>, LogParserError> {
+    let data = try!(Json::from_str(step_data));
+    let empty = vec![];
+    let steps = data.find("steps").and_then(|x| x.as_array()).unwrap_or(&empty);
+    let mut rv = vec![];
+    for step in steps.iter() {
+        let name = step.find("name").and_then(|x| x.as_string()).unwrap_or("");
+        for error in step.find("errors").and_then(|x| x.as_array()).unwrap_or(&empty).iter() {
+            let line = error.find("line").and_then(|x| x.as_string()).unwrap_or("");
+            let normalized = match error.find("normalized").and_then(|x| x.as_string()) {
+                Some(normalized) => normalized.into(),
+                None => normalize_line(line)
+            };
+            rv.push((name.into(),
+                     error.find("linenumber").and_then(|x| x.as_u64()).unwrap_or(0) as u32,
+                     line.into(),
+                     normalized));
+        }
+    }
+    Ok(rv)
+}
+
+/// Group the error lines of several step_data artifacts, each given with
+/// the name of its log. Lines with the same normalized text always share a
+/// cluster; otherwise a line joins the first cluster whose representative
+/// is at least options.similarity alike.
+pub fn cluster_step_data(logs: &[(String, String)], options: &ClusterOptions)
+                         -> Result<Vec<Cluster>, LogParserError> {
+    let mut builders: Vec<ClusterBuilder> = vec![];
+    let mut by_normalized: HashMap<String, usize> = HashMap::new();
+    for &(ref log, ref data) in logs.iter() {
+        for (step, line_number, line, normalized) in try!(read_errors(data)) {
+            let idx = match by_normalized.get(&normalized).cloned() {
+                Some(idx) => idx,
+                None => {
+                    let line_words = words(&normalized);
+                    let similar = if options.similarity < 1.0 {
+                        builders.iter().position(
+                            |x| similarity(&x.words, &line_words) >= options.similarity)
+                    } else {
+                        None
+                    };
+                    let idx = match similar {
+                        Some(idx) => idx,
+                        None => {
+                            builders.push(ClusterBuilder {
+                                cluster: Cluster {
+                                    fingerprint: fingerprint(&normalized),
+                                    normalized: normalized.clone(),
+                                    representative: line.clone(),
+                                    logs: vec![],
+                                    members: vec![]
+                                },
+                                words: line_words
+                            });
+                            builders.len() - 1
+                        }
+                    };
+                    by_normalized.insert(normalized, idx);
+                    idx
+                }
+            };
+            let cluster = &mut builders[idx].cluster;
+            if cluster.logs.last() != Some(log) {
+                cluster.logs.push(log.clone());
+            }
+            cluster.members.push(ClusterMember {
+                log: log.clone(),
+                step: step,
+                linenumber: line_number,
+                line: line
+            });
+        }
+    }
+    let mut clusters: Vec<Cluster> = builders.into_iter().map(|x| x.cluster).collect();
+    // The sort is stable, so clusters in as many logs stay in order of appearance
+    clusters.sort_by(|a, b| b.logs.len().cmp(&a.logs.len()));
+    Ok(clusters)
+}
+
+fn run_worker(jobs: Arc<Mutex<Receiver<(usize, String)>>>,
+              results: Sender<(usize, Result<String, LogParserError>)>, user_agent: String,
+              parse_options: ParseOptions) {
+    loop {
+        let job = match jobs.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return
+        };
+        let (idx, source) = match job {
+            Ok(x) => x,
+            Err(_) => return
+        };
+        if results.send((idx, step_data(&source, &user_agent, &parse_options))).is_err() {
+            return;
+        }
+    }
+}
+
+/// Parse a batch of logs, each a URL or local path, and cluster their
+/// error lines. Logs that can't be parsed are reported as failures rather
+/// than failing the batch. parse_options apply to the parse of each log.
+pub fn cluster_logs(sources: &[String], user_agent: &str, options: &ClusterOptions,
+                    parse_options: &ParseOptions) -> Result<ClusterReport, LogParserError> {
+    let (job_sender, job_receiver) = channel();
+    let job_receiver = Arc::new(Mutex::new(job_receiver));
+    let (result_sender, result_receiver) = channel();
+    for _ in 0..options.workers.max(1) {
+        let jobs = job_receiver.clone();
+        let results = result_sender.clone();
+        let user_agent = user_agent.to_string();
+        let parse_options = parse_options.clone();
+        thread::spawn(move || run_worker(jobs, results, user_agent, parse_options));
+    }
+    drop(result_sender);
+    for (idx, source) in sources.iter().enumerate() {
+        job_sender.send((idx, source.clone())).expect("Cluster workers stopped");
+    }
+    drop(job_sender);
+
+    let mut results: Vec<Option<Result<String, LogParserError>>> = sources.iter().map(|_| None).collect();
+    for (idx, result) in result_receiver.iter() {
+        results[idx] = Some(result);
+    }
+    let mut step_data = vec![];
+    let mut failures = vec![];
+    for (source, result) in sources.iter().zip(results.into_iter()) {
+        match result {
+            Some(Ok(data)) => step_data.push((source.clone(), data)),
+            Some(Err(e)) => failures.push(LogFailure {
+                log: source.clone(),
+                error: format!("{}: {}", e.name(), e.description())
+            }),
+            None => failures.push(LogFailure {
+                log: source.clone(),
+                error: "Parse thread panicked".into()
+            })
+        }
+    }
+    Ok(ClusterReport {
+        clusters: try!(cluster_step_data(&step_data, options)),
+        failures: failures
+    })
+}
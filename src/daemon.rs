@@ -0,0 +1,411 @@
+use cache::ResultCache;
+use http::is_retryable;
+use limits::CancelHandle;
+use logparser::LogParserError;
+use rustc_serialize::json;
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use {parse_log_with_options, ParseOptions};
+
+/// A request to parse a log, as found in a queue message.
+#[derive(RustcDecodable, RustcEncodable, Clone)]
+pub struct JobDescriptor {
+    pub job_id: String,
+    pub log_url: String,
+    /// Names of the parsers to run, or all of them if missing
+    pub parsers: Option<Vec<String>>
+}
+
+/// A message from a JobSource. The id identifies it when acknowledging it.
+pub struct Delivery {
+    pub id: u64,
+    pub body: Vec<u8>,
+    /// Number of earlier attempts at the job that failed and were requeued
+    pub attempts: u32
+}
+
+/// A queue of job descriptors. Each delivery is eventually acknowledged,
+/// requeued or dead-lettered.
+pub trait JobSource {
+    /// Get the next message, or None if there isn't one available within
+    /// a short time.
+    fn next(&mut self) -> Result<Option<Delivery>, LogParserError>;
+    /// Remove a message that was handled successfully.
+    fn ack(&mut self, id: u64) -> Result<(), LogParserError>;
+    /// Return a message to the queue, so it is delivered again with the
+    /// given number of attempts.
+    fn requeue(&mut self, id: u64, attempts: u32) -> Result<(), LogParserError>;
+    /// Remove a message that can't be handled, keeping it and the error
+    /// somewhere it can be inspected.
+    fn dead_letter(&mut self, id: u64, error: &LogParserError) -> Result<(), LogParserError>;
+}
+
+/// Somewhere to write the artifacts from each job.
+pub trait ResultSink {
+    fn write(&mut self, job: &JobDescriptor, artifacts: &[(&'static str, String)])
+             -> Result<(), LogParserError>;
+}
+
+/// Format the artifacts of a job as a single JSON object.
+fn result_json(job: &JobDescriptor, artifacts: &[(&'static str, String)]) -> String {
+    let mut rv = format!("{{\"job_id\": {}, \"logurl\": {}, \"artifacts\": {{",
+                         json::encode(&job.job_id).unwrap(), json::encode(&job.log_url).unwrap());
+    for (idx, &(name, ref artifact)) in artifacts.iter().enumerate() {
+        if idx > 0 {
+            rv.push_str(", ");
+        }
+        rv.push_str(&*format!("\"{}\": {}", name, artifact));
+    }
+    rv.push_str("}}");
+    rv
+}
+
+/// Make a job id safe to use as a file name.
+fn file_name(job_id: &str) -> String {
+    job_id.chars()
+        .map(|x| if x.is_alphanumeric() || x == '-' || x == '_' { x } else { '_' })
+        .collect()
+}
+
+/// Writes the result of each job to <job_id>.json in a directory.
+pub struct DirectorySink {
+    dir: PathBuf
+}
+
+impl DirectorySink {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<DirectorySink, LogParserError> {
+        let dir = dir.into();
+        try!(fs::create_dir_all(&dir));
+        Ok(DirectorySink {
+            dir: dir
+        })
+    }
+}
+
+impl ResultSink for DirectorySink {
+    fn write(&mut self, job: &JobDescriptor, artifacts: &[(&'static str, String)])
+             -> Result<(), LogParserError> {
+        let name = file_name(&job.job_id);
+        // Write to a temporary file first so readers never see a partial result
+        let temp_path = self.dir.join(format!("{}.json.tmp", name));
+        try!(try!(File::create(&temp_path)).write_all(result_json(job, artifacts).as_bytes()));
+        try!(fs::rename(&temp_path, self.dir.join(format!("{}.json", name))));
+        Ok(())
+    }
+}
+
+/// Writes the result of each job to stdout, one JSON object per line.
+pub struct StdoutSink;
+
+impl ResultSink for StdoutSink {
+    fn write(&mut self, job: &JobDescriptor, artifacts: &[(&'static str, String)])
+             -> Result<(), LogParserError> {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        try!(writeln!(stdout, "{}", result_json(job, artifacts)));
+        try!(stdout.flush());
+        Ok(())
+    }
+}
+
+static ATTEMPTS_SUFFIX: &'static str = ".attempt-";
+
+/// Split a spool file name into the name it was first queued under,
+/// without the extension, and the number of attempts recorded in it.
+fn split_attempts(name: &str) -> (&str, u32) {
+    let stem = name.trim_right_matches(".json");
+    if let Some(pos) = stem.rfind(ATTEMPTS_SUFFIX) {
+        if let Ok(attempts) = stem[pos + ATTEMPTS_SUFFIX.len()..].parse() {
+            return (&stem[..pos], attempts);
+        }
+    }
+    (stem, 0)
+}
+
+/// A queue of job descriptor files in a directory. Files are claimed by
+/// moving them to processing/, and removed when acknowledged or moved to
+/// dead/, alongside a .error file, when dead-lettered. Requeued files are
+/// renamed to <name>.attempt-<n>.json to record the attempts. Producers should
+/// write each file under another name and rename it into place. Only one
+/// daemon should read from each spool.
+pub struct SpoolSource {
+    dir: PathBuf,
+    next_id: u64,
+    claimed: HashMap<u64, String>
+}
+
+impl SpoolSource {
+    /// Open a spool, returning any files left in processing/ by a daemon
+    /// that stopped part way through to the queue.
+    pub fn open<P: Into<PathBuf>>(dir: P) -> Result<SpoolSource, LogParserError> {
+        let dir = dir.into();
+        for subdir in ["processing", "dead"].iter() {
+            try!(fs::create_dir_all(dir.join(subdir)));
+        }
+        for name in try!(SpoolSource::list(&dir.join("processing"))).iter() {
+            try!(fs::rename(dir.join("processing").join(name), dir.join(name)));
+        }
+        Ok(SpoolSource {
+            dir: dir,
+            next_id: 0,
+            claimed: HashMap::new()
+        })
+    }
+
+    /// Names of the .json files in a directory, oldest (by name) first.
+    fn list(dir: &Path) -> Result<Vec<String>, LogParserError> {
+        let mut names = vec![];
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            if let Some(name) = entry.file_name().to_str() {
+                if name.ends_with(".json") && try!(entry.file_type()).is_file() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn take(&mut self, id: u64) -> Result<String, LogParserError> {
+        self.claimed.remove(&id).ok_or_else(
+            || LogParserError::Other(format!("Unknown spool delivery {}", id)))
+    }
+}
+
+impl JobSource for SpoolSource {
+    fn next(&mut self) -> Result<Option<Delivery>, LogParserError> {
+        for name in try!(SpoolSource::list(&self.dir)).into_iter() {
+            let path = self.dir.join("processing").join(&name);
+            if fs::rename(self.dir.join(&name), &path).is_err() {
+                // Removed since it was listed
+                continue;
+            }
+            let mut body = vec![];
+            try!(try!(File::open(&path)).read_to_end(&mut body));
+            let attempts = split_attempts(&name).1;
+            self.next_id += 1;
+            self.claimed.insert(self.next_id, name);
+            return Ok(Some(Delivery {
+                id: self.next_id,
+                body: body,
+                attempts: attempts
+            }));
+        }
+        Ok(None)
+    }
+
+    fn ack(&mut self, id: u64) -> Result<(), LogParserError> {
+        let name = try!(self.take(id));
+        try!(fs::remove_file(self.dir.join("processing").join(name)));
+        Ok(())
+    }
+
+    fn requeue(&mut self, id: u64, attempts: u32) -> Result<(), LogParserError> {
+        let name = try!(self.take(id));
+        let queued = match split_attempts(&name) {
+            (stem, _) if attempts == 0 => format!("{}.json", stem),
+            (stem, _) => format!("{}{}{}.json", stem, ATTEMPTS_SUFFIX, attempts)
+        };
+        try!(fs::rename(self.dir.join("processing").join(&name), self.dir.join(queued)));
+        Ok(())
+    }
+
+    fn dead_letter(&mut self, id: u64, error: &LogParserError) -> Result<(), LogParserError> {
+        let name = try!(self.take(id));
+        let dead = self.dir.join("dead");
+        try!(try!(File::create(dead.join(format!("{}.error", name))))
+             .write_all(format!("{}: {}\n", error.name(), error.description()).as_bytes()));
+        try!(fs::rename(self.dir.join("processing").join(&name), dead.join(&name)));
+        Ok(())
+    }
+}
+
+pub struct DaemonOptions {
+    /// Number of logs to parse at once
+    pub workers: usize,
+    pub user_agent: String,
+    /// Deadline for each parse
+    pub deadline: Option<Duration>,
+    /// Directory for the result cache, if any
+    pub cache_dir: Option<PathBuf>,
+    /// How long to wait for results when the source is empty
+    pub poll_interval: Duration,
+    /// Number of times to try a job that fails with a transient error
+    /// before dead-lettering it
+    pub max_attempts: u32,
+    /// How long to wait before requeueing a job after a transient error,
+    /// doubled for each earlier attempt
+    pub retry_backoff: Duration
+}
+
+impl DaemonOptions {
+    pub fn new() -> DaemonOptions {
+        DaemonOptions {
+            workers: 4,
+            user_agent: "Log Parser Daemon".into(),
+            deadline: Some(Duration::new(600, 0)),
+            cache_dir: None,
+            poll_interval: Duration::from_millis(500),
+            max_attempts: 5,
+            retry_backoff: Duration::new(30, 0)
+        }
+    }
+}
+
+/// Whether a job that failed with this error may succeed later. Corrupt
+/// logs fail with I/O errors too, such as an unexpected end of file from a
+/// truncated gzip stream, so only I/O errors from the connection count.
+fn is_transient(err: &LogParserError) -> bool {
+    match *err {
+        LogParserError::Io(ref e) => match e.kind() {
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset |
+            io::ErrorKind::ConnectionAborted | io::ErrorKind::NotConnected |
+            io::ErrorKind::BrokenPipe | io::ErrorKind::TimedOut |
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => true,
+            _ => false
+        },
+        ref e => is_retryable(e)
+    }
+}
+
+/// How long to wait before requeueing a job that has failed attempts times.
+fn retry_delay(options: &DaemonOptions, attempts: u32) -> Duration {
+    options.retry_backoff * (1 << cmp::min(attempts.saturating_sub(1), 5))
+}
+
+// A job waiting to be requeued: when, the delivery id and its attempts
+type Retry = (Instant, u64, u32);
+
+/// Requeue the jobs waiting to be retried whose time has come, or all of
+/// them, so none are left claimed when the daemon stops.
+fn requeue_retries(source: &mut JobSource, retries: &mut Vec<Retry>, all: bool)
+                   -> Result<(), LogParserError> {
+    let now = Instant::now();
+    while let Some(pos) = retries.iter().position(|x| all || x.0 <= now) {
+        let (_, id, attempts) = retries.remove(pos);
+        try!(source.requeue(id, attempts));
+    }
+    Ok(())
+}
+
+type Job = (u64, u32, JobDescriptor);
+
+type JobResult = (u64, u32, JobDescriptor, Result<Vec<(&'static str, String)>, LogParserError>);
+
+fn run_worker(jobs: Arc<Mutex<Receiver<Job>>>,
+              results: Sender<JobResult>,
+              user_agent: String, deadline: Option<Duration>, cache_dir: Option<PathBuf>) {
+    loop {
+        let job = match jobs.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return
+        };
+        let (id, attempts, job) = match job {
+            Ok(x) => x,
+            // The daemon has stopped
+            Err(_) => return
+        };
+        let mut options = ParseOptions::new();
+        options.parsers = job.parsers.clone();
+        options.limits.deadline = deadline;
+        options.cache = cache_dir.as_ref().map(|x| ResultCache::new(x.clone()));
+        // Always send a result, so the daemon knows the job is no longer in progress
+        let result = panic::catch_unwind(AssertUnwindSafe(
+            || parse_log_with_options(&job.log_url, &user_agent, &options)))
+            .unwrap_or_else(|_| Err(LogParserError::Other("Parse panicked".into())));
+        if results.send((id, attempts, job, result)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Parse the log for each job from source on a pool of workers, writing
+/// the artifacts to sink. Messages are only acknowledged once the artifacts
+/// are written. Jobs that fail with a network error or a 5xx status are
+/// requeued to try again after a backoff, until they have been tried
+/// max_attempts times; jobs that fail otherwise are dead-lettered. Runs
+/// until stop is cancelled and the jobs in progress are finished, or until
+/// the source or sink fails.
+pub fn run_daemon(source: &mut JobSource, sink: &mut ResultSink, options: &DaemonOptions,
+                  stop: &CancelHandle) -> Result<(), LogParserError> {
+    let (job_sender, job_receiver) = channel();
+    let job_receiver = Arc::new(Mutex::new(job_receiver));
+    let (result_sender, result_receiver) = channel();
+    for _ in 0..options.workers {
+        let jobs = job_receiver.clone();
+        let results = result_sender.clone();
+        let user_agent = options.user_agent.clone();
+        let deadline = options.deadline;
+        let cache_dir = options.cache_dir.clone();
+        thread::spawn(move || run_worker(jobs, results, user_agent, deadline, cache_dir));
+    }
+    // Only the workers hold these, so the channels disconnect if they all stop
+    drop(job_receiver);
+    drop(result_sender);
+
+    let mut in_progress = 0;
+    let mut retries = vec![];
+    loop {
+        let stopping = stop.is_cancelled();
+        try!(requeue_retries(source, &mut retries, stopping));
+        if stopping && in_progress == 0 {
+            return Ok(());
+        }
+        if !stopping && in_progress < options.workers {
+            if let Some(delivery) = try!(source.next()) {
+                let body = String::from_utf8_lossy(&delivery.body).into_owned();
+                match json::decode::<JobDescriptor>(&body) {
+                    Ok(job) => {
+                        if job_sender.send((delivery.id, delivery.attempts, job)).is_err() {
+                            try!(source.requeue(delivery.id, delivery.attempts));
+                            try!(requeue_retries(source, &mut retries, true));
+                            return Err(LogParserError::Other("Daemon workers stopped".into()));
+                        }
+                        in_progress += 1;
+                    },
+                    Err(e) => try!(source.dead_letter(delivery.id, &LogParserError::Other(
+                        format!("Invalid job descriptor: {}", e))))
+                }
+                continue;
+            }
+        }
+        let (id, attempts, job, result) = match result_receiver.recv_timeout(options.poll_interval) {
+            Ok(x) => x,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+                try!(requeue_retries(source, &mut retries, true));
+                return Err(LogParserError::Other("Daemon workers stopped".into()));
+            }
+        };
+        in_progress -= 1;
+        match result {
+            Ok(artifacts) => {
+                if let Err(e) = sink.write(&job, &artifacts) {
+                    // Leave the job for a later attempt, once the sink is fixed
+                    try!(source.requeue(id, attempts));
+                    try!(requeue_retries(source, &mut retries, true));
+                    return Err(e);
+                }
+                try!(source.ack(id));
+            },
+            Err(ref e) if is_transient(e) && attempts + 1 < options.max_attempts => {
+                retries.push((Instant::now() + retry_delay(options, attempts + 1), id, attempts + 1));
+            },
+            Err(ref e) if is_transient(e) => {
+                try!(source.dead_letter(id, &LogParserError::Other(
+                    format!("Failed after {} attempts: {}: {}", attempts + 1, e.name(), e.description()))));
+            },
+            Err(e) => try!(source.dead_letter(id, &e))
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use logparser::LogParserError;
+use normalize::normalize_line;
+use rustc_serialize::json::Json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::Read;
+use std::thread;
+use {parse_bytes_with_options, parse_log_with_options, ParseOptions};
+
+/// An error line found in one or both of the logs.
+#[derive(RustcEncodable)]
+pub struct ErrorDiff {
+    /// The line with the parts that differ between runs replaced
+    pub normalized: String,
+    /// The line as it appears in the new log, or the base log if it's gone
+    pub line: String,
+    pub base_linenumber: Option<u32>,
+    pub new_linenumber: Option<u32>
+}
+
+/// The differences in a step that appears in either log. Steps are matched
+/// by name, and by order among steps with the same name.
+#[derive(RustcEncodable)]
+pub struct StepDiff {
+    pub name: String,
+    /// The step results, or None if the step isn't in that log
+    pub base_result: Option<String>,
+    pub new_result: Option<String>,
+    /// Duration in seconds, where it's known for the step in each log
+    pub base_duration: Option<i64>,
+    pub new_duration: Option<i64>,
+    pub duration_delta: Option<i64>,
+    pub new_errors: Vec<ErrorDiff>,
+    pub gone_errors: Vec<ErrorDiff>,
+    pub persistent_errors: Vec<ErrorDiff>
+}
+
+#[derive(RustcEncodable)]
+pub struct LogDiff {
+    pub steps: Vec<StepDiff>
+}
+
+struct StepInfo {
+    name: String,
+    result: Option<String>,
+    duration: Option<i64>,
+    /// Line number, line and normalized line of each error
+    errors: Vec<(u32, String, String)>
+}
+
+fn read_steps(step_data: &str) -> Result<Vec<StepInfo>, LogParserError> {
+    let data = try!(Json::from_str(step_data));
+    let empty = vec![];
+    let steps = data.find("steps").and_then(|x| x.as_array()).unwrap_or(&empty);
+    Ok(steps.iter().map(|step| {
+        let errors = step.find("errors").and_then(|x| x.as_array()).unwrap_or(&empty);
+        StepInfo {
+            name: step.find("name").and_then(|x| x.as_string()).unwrap_or("").into(),
+            result: step.find("result").and_then(|x| x.as_string()).map(|x| x.into()),
+            duration: step.find("duration").and_then(|x| x.as_i64()),
+            errors: errors.iter().map(|error| {
+                let line = error.find("line").and_then(|x| x.as_string()).unwrap_or("");
+                // Older step_data doesn't include the normalized line
+                let normalized = match error.find("normalized").and_then(|x| x.as_string()) {
+                    Some(normalized) => normalized.into(),
+                    None => normalize_line(line)
+                };
+                (error.find("linenumber").and_then(|x| x.as_u64()).unwrap_or(0) as u32,
+                 line.into(), normalized)
+            }).collect()
+        }
+    }).collect())
+}
+
+/// Identify each step by its name and the number of earlier steps with the same name.
+fn step_keys(steps: &[StepInfo]) -> Vec<(String, usize)> {
+    let mut counts = HashMap::new();
+    steps.iter().map(|step| {
+        let count = counts.entry(step.name.clone()).or_insert(0);
+        *count += 1;
+        (step.name.clone(), *count - 1)
+    }).collect()
+}
+
+fn diff_errors(base: Option<&StepInfo>, new: Option<&StepInfo>, diff: &mut StepDiff) {
+    let no_errors = vec![];
+    let base_errors = base.map(|x| &x.errors).unwrap_or(&no_errors);
+    let new_errors = new.map(|x| &x.errors).unwrap_or(&no_errors);
+    let mut unmatched: HashMap<&str, VecDeque<usize>> = HashMap::new();
+    for (idx, &(_, _, ref normalized)) in base_errors.iter().enumerate() {
+        unmatched.entry(&**normalized).or_insert_with(VecDeque::new).push_back(idx);
+    }
+    let mut matched = HashSet::new();
+    for &(line_number, ref line, ref normalized) in new_errors.iter() {
+        let base_idx = unmatched.get_mut(&**normalized).and_then(|x| x.pop_front());
+        let error = ErrorDiff {
+            normalized: normalized.clone(),
+            line: line.clone(),
+            base_linenumber: base_idx.map(|idx| base_errors[idx].0),
+            new_linenumber: Some(line_number)
+        };
+        match base_idx {
+            Some(idx) => {
+                matched.insert(idx);
+                diff.persistent_errors.push(error);
+            },
+            None => diff.new_errors.push(error)
+        }
+    }
+    for (idx, &(line_number, ref line, ref normalized)) in base_errors.iter().enumerate() {
+        if !matched.contains(&idx) {
+            diff.gone_errors.push(ErrorDiff {
+                normalized: normalized.clone(),
+                line: line.clone(),
+                base_linenumber: Some(line_number),
+                new_linenumber: None
+            });
+        }
+    }
+}
+
+fn diff_step(base: Option<&StepInfo>, new: Option<&StepInfo>) -> StepDiff {
+    let base_duration = base.and_then(|x| x.duration);
+    let new_duration = new.and_then(|x| x.duration);
+    let mut diff = StepDiff {
+        name: new.or(base).map(|x| x.name.clone()).unwrap_or_else(String::new),
+        base_result: base.and_then(|x| x.result.clone()),
+        new_result: new.and_then(|x| x.result.clone()),
+        base_duration: base_duration,
+        new_duration: new_duration,
+        duration_delta: match (base_duration, new_duration) {
+            (Some(base), Some(new)) => Some(new - base),
+            _ => None
+        },
+        new_errors: vec![],
+        gone_errors: vec![],
+        persistent_errors: vec![]
+    };
+    diff_errors(base, new, &mut diff);
+    diff
+}
+
+/// Compare two step_data artifacts. The steps are listed in the order of
+/// the new log, with steps only in the base log placed after the step they
+/// followed there.
+pub fn diff_step_data(base: &str, new: &str) -> Result<LogDiff, LogParserError> {
+    let base_steps = try!(read_steps(base));
+    let new_steps = try!(read_steps(new));
+    let base_keys = step_keys(&base_steps);
+    let new_keys = step_keys(&new_steps);
+    let base_positions: HashMap<&(String, usize), usize> =
+        base_keys.iter().enumerate().map(|(idx, key)| (key, idx)).collect();
+    let new_key_set: HashSet<&(String, usize)> = new_keys.iter().collect();
+
+    let mut steps = Vec::with_capacity(base_steps.len() + new_steps.len());
+    let mut next_base = 0;
+    for (new_step, key) in new_steps.iter().zip(new_keys.iter()) {
+        match base_positions.get(key) {
+            Some(&pos) => {
+                if pos >= next_base {
+                    for idx in next_base..pos {
+                        if !new_key_set.contains(&base_keys[idx]) {
+                            steps.push(diff_step(Some(&base_steps[idx]), None));
+                        }
+                    }
+                    next_base = pos + 1;
+                }
+                steps.push(diff_step(Some(&base_steps[pos]), Some(new_step)));
+            },
+            None => steps.push(diff_step(None, Some(new_step)))
+        }
+    }
+    for idx in next_base..base_steps.len() {
+        if !new_key_set.contains(&base_keys[idx]) {
+            steps.push(diff_step(Some(&base_steps[idx]), None));
+        }
+    }
+    Ok(LogDiff {
+        steps: steps
+    })
+}
+
+/// Get the step_data for a log at a URL or a local path, including the
+/// steps of a log without any errors. Like parse_log, logs at URLs are
+/// gzipped; local files are gzipped if their names end in .gz. Only the step
+/// parser is run, with the rest of the options as given.
+pub fn step_data(source: &str, user_agent: &str, options: &ParseOptions)
+                 -> Result<String, LogParserError> {
+    let mut options = options.clone();
+    options.parsers = Some(vec!["step_data".into()]);
+    options.always_step_data = true;
+    let artifacts = if source.starts_with("http://") || source.starts_with("https://") {
+        try!(parse_log_with_options(source, user_agent, &options))
+    } else {
+        let mut data = vec![];
+        try!(try!(File::open(source)).read_to_end(&mut data));
+        try!(parse_bytes_with_options(data, source.ends_with(".gz"), &options))
+    };
+    match artifacts.into_iter().find(|x| x.0 == "step_data") {
+        Some((_, artifact)) => Ok(artifact),
+        None => Err(LogParserError::Other(format!("No step_data for {}", source)))
+    }
+}
+
+/// Parse two logs, each a URL or local path, and compare their steps.
+pub fn diff_logs(base: &str, new: &str, user_agent: &str, options: &ParseOptions)
+                 -> Result<LogDiff, LogParserError> {
+    let base_source = base.to_string();
+    let base_user_agent = user_agent.to_string();
+    let base_options = options.clone();
+    let base_data = thread::spawn(move || step_data(&base_source, &base_user_agent, &base_options));
+    let new_data = try!(step_data(new, user_agent, options));
+    let base_data = try!(base_data.join().unwrap_or_else(
+        |_| Err(LogParserError::Other("Parse thread panicked".into()))));
+    diff_step_data(&base_data, &new_data)
+}
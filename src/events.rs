@@ -0,0 +1,174 @@
+use follow::{follow_log, FollowOptions};
+use logparser::LogParserError;
+use rustc_serialize::json;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use {parse_log_with_options, ParseOptions};
+
+/// Something found in a log, reported while the parse is still running.
+#[derive(Clone, Debug)]
+pub enum ParseEvent {
+    StepStarted {
+        name: String,
+        linenumber: u32,
+        started: Option<String>
+    },
+    /// An error line, as it appears in the step_data artifact
+    ErrorLine {
+        step: String,
+        linenumber: u32,
+        line: String,
+        fingerprint: String
+    },
+    StepFinished {
+        name: String,
+        linenumber: u32,
+        result: String,
+        error_count: u32,
+        duration: Option<i64>
+    },
+    /// The PERFHERDER_DATA JSON, as it appears in the log
+    PerfDatum {
+        linenumber: u32,
+        data: String
+    },
+    /// A TinderboxPrint line, as JSON in the form used by the job_details artifact
+    JobDetail {
+        linenumber: u32,
+        detail: String
+    },
+    /// An artifact for the lines seen so far, from a log that is still growing
+    Snapshot {
+        name: String,
+        artifact: String
+    }
+}
+
+impl ParseEvent {
+    /// Format the event as a single line JSON object, with its type in the
+    /// "event" field.
+    pub fn to_json(&self) -> String {
+        match *self {
+            ParseEvent::StepStarted { ref name, linenumber, ref started } => {
+                format!("{{\"event\": \"step_started\", \"name\": {}, \"linenumber\": {}, \"started\": {}}}",
+                        json::encode(name).unwrap(), linenumber, json::encode(started).unwrap())
+            },
+            ParseEvent::ErrorLine { ref step, linenumber, ref line, ref fingerprint } => {
+                format!("{{\"event\": \"error_line\", \"step\": {}, \"linenumber\": {}, \"line\": {}, \
+                         \"fingerprint\": {}}}",
+                        json::encode(step).unwrap(), linenumber, json::encode(line).unwrap(),
+                        json::encode(fingerprint).unwrap())
+            },
+            ParseEvent::StepFinished { ref name, linenumber, ref result, error_count, duration } => {
+                format!("{{\"event\": \"step_finished\", \"name\": {}, \"linenumber\": {}, \
+                         \"result\": {}, \"error_count\": {}, \"duration\": {}}}",
+                        json::encode(name).unwrap(), linenumber, json::encode(result).unwrap(),
+                        error_count, json::encode(&duration).unwrap())
+            },
+            // Kept as a string for the same reason as in the performance_data artifact
+            ParseEvent::PerfDatum { linenumber, ref data } => {
+                format!("{{\"event\": \"perf_datum\", \"linenumber\": {}, \"data\": {}}}",
+                        linenumber, json::encode(data).unwrap())
+            },
+            ParseEvent::JobDetail { linenumber, ref detail } => {
+                format!("{{\"event\": \"job_detail\", \"linenumber\": {}, \"detail\": {}}}",
+                        linenumber, detail)
+            },
+            ParseEvent::Snapshot { ref name, ref artifact } => {
+                format!("{{\"event\": \"snapshot\", \"name\": {}, \"artifact\": {}}}",
+                        json::encode(name).unwrap(), artifact)
+            }
+        }
+    }
+}
+
+/// A function called with each event. With the parallel parser it is
+/// called from several threads.
+pub type EventCallback = Arc<Fn(&ParseEvent) + Send + Sync>;
+
+/// Where a parser sends its events; they are dropped unless a callback is set.
+#[derive(Clone)]
+pub struct EventSink {
+    callback: Option<EventCallback>
+}
+
+impl EventSink {
+    pub fn new(callback: EventCallback) -> EventSink {
+        EventSink {
+            callback: Some(callback)
+        }
+    }
+
+    pub fn none() -> EventSink {
+        EventSink {
+            callback: None
+        }
+    }
+
+    /// Send an event, only creating it if there is a callback.
+    pub fn emit<F: FnOnce() -> ParseEvent>(&self, event: F) {
+        if let Some(ref callback) = self.callback {
+            callback(&event());
+        }
+    }
+}
+
+/// The events from a parse running on another thread, ending when the
+/// parse does.
+pub struct EventStream {
+    receiver: Receiver<ParseEvent>,
+    parse: JoinHandle<Result<Vec<(&'static str, String)>, LogParserError>>
+}
+
+impl EventStream {
+    /// Wait for the parse to finish, returning its artifacts. Any events
+    /// not yet read are dropped.
+    pub fn finish(self) -> Result<Vec<(&'static str, String)>, LogParserError> {
+        drop(self.receiver);
+        match self.parse.join() {
+            Ok(result) => result,
+            Err(_) => Err(LogParserError::Other("Parse thread panicked".into()))
+        }
+    }
+}
+
+impl Iterator for EventStream {
+    type Item = ParseEvent;
+
+    fn next(&mut self) -> Option<ParseEvent> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Parse a log on another thread, returning an iterator over the events
+/// as they are found. Logs answered from the cache have no events.
+pub fn parse_log_events(url: &str, user_agent: &str, options: ParseOptions) -> EventStream {
+    let url = url.to_string();
+    let user_agent = user_agent.to_string();
+    spawn_parse(options, move |options| parse_log_with_options(&url, &user_agent, options))
+}
+
+/// Like parse_log_events, but following a log that is still being written,
+/// with snapshots of the artifacts while it grows.
+pub fn follow_log_events(source: &str, user_agent: &str, options: ParseOptions,
+                         follow: FollowOptions) -> EventStream {
+    let source = source.to_string();
+    let user_agent = user_agent.to_string();
+    spawn_parse(options, move |options| follow_log(&source, &user_agent, options, &follow))
+}
+
+fn spawn_parse<F>(options: ParseOptions, parse: F) -> EventStream
+    where F: FnOnce(&ParseOptions) -> Result<Vec<(&'static str, String)>, LogParserError> + Send + 'static {
+    let (sender, receiver) = channel();
+    let sender = Mutex::new(sender);
+    let mut options = options;
+    options.events = Some(Arc::new(move |event: &ParseEvent| {
+        // The stream may have been finished early
+        let _ = sender.lock().unwrap().send(event.clone());
+    }));
+    EventStream {
+        receiver: receiver,
+        parse: thread::spawn(move || parse(&options))
+    }
+}
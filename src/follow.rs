@@ -0,0 +1,305 @@
+use classifier::Classifier;
+use events::{EventSink, ParseEvent};
+use http::get_live;
+use limits::{CancelHandle, LimitedReader, LimitState};
+use lines::LineReader;
+use logparser::{LogParser, LogParserError};
+use metadata::Failure;
+use metrics::ParseStats;
+use prefilter::{ParserSet, Prefilter};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use {classify_and_describe, finish_all, is_complete, parse_line, ParseOptions};
+
+pub struct FollowOptions {
+    /// How often to check a local file for new data
+    pub poll_interval: Duration,
+    /// Treat the log as finished once it hasn't grown for this long. Live
+    /// logs over HTTP are reconnected instead, and normally end when the
+    /// server closes the stream.
+    pub idle_timeout: Option<Duration>,
+    /// How often to publish snapshots of the artifacts while the log grows
+    pub snapshot_interval: Duration
+}
+
+impl FollowOptions {
+    pub fn new() -> FollowOptions {
+        FollowOptions {
+            poll_interval: Duration::new(1, 0),
+            idle_timeout: Some(Duration::new(600, 0)),
+            snapshot_interval: Duration::new(10, 0)
+        }
+    }
+}
+
+/// A local file that is still being written. Reading waits for more data,
+/// rather than ending, until the file hasn't grown for the idle timeout.
+pub struct FollowFile {
+    file: File,
+    poll_interval: Duration,
+    idle_timeout: Option<Duration>,
+    last_data: Instant,
+    limits: Arc<LimitState>,
+    stop: Option<CancelHandle>
+}
+
+impl FollowFile {
+    pub fn new(file: File, options: &FollowOptions, limits: Arc<LimitState>) -> FollowFile {
+        FollowFile {
+            file: file,
+            poll_interval: options.poll_interval,
+            idle_timeout: options.idle_timeout,
+            last_data: Instant::now(),
+            limits: limits,
+            stop: None
+        }
+    }
+
+    /// End the file once the handle is cancelled, as if it had stopped growing.
+    pub fn set_stop(&mut self, stop: CancelHandle) {
+        self.stop = Some(stop);
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.as_ref().map(|x| x.is_cancelled()).unwrap_or(false)
+    }
+}
+
+impl Read for FollowFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = try!(self.file.read(buf));
+            if len > 0 {
+                self.last_data = Instant::now();
+                return Ok(len);
+            }
+            if self.stopped() || self.idle_timeout.map(|x| self.last_data.elapsed() >= x).unwrap_or(false) {
+                return Ok(0);
+            }
+            // Waiting doesn't go through LimitedReader, so check the limits here
+            if self.limits.check_time().is_some() {
+                return Err(io::Error::new(io::ErrorKind::Other, "Parse stopped early"));
+            }
+            thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+/// A line number, the byte offsets of the start and end of the line, and the line.
+type LineMessage = Result<(u32, u64, u64, String), LogParserError>;
+
+// Number of lines the reader may get ahead of the parsers
+static LINE_BUFFER: usize = 1024;
+
+/// Reader that ends once the handle is cancelled.
+struct StoppableReader<R> {
+    inner: R,
+    stop: CancelHandle
+}
+
+impl<R: Read> Read for StoppableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.stop.is_cancelled() {
+            return Ok(0);
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// The thread reading the log, and the lines it has read. The thread is
+/// stopped when this is dropped, however the parse ends, and joined if it
+/// reads a local file. A live log isn't waited for, as a read may be
+/// blocked on the connection until the idle timeout; the thread ends on
+/// its own once that read returns.
+struct ReaderThread {
+    stop: CancelHandle,
+    lines: Option<Receiver<LineMessage>>,
+    handle: Option<JoinHandle<()>>
+}
+
+impl ReaderThread {
+    fn recv_timeout(&self, timeout: Duration) -> Result<LineMessage, RecvTimeoutError> {
+        self.lines.as_ref().expect("Reader thread already stopped").recv_timeout(timeout)
+    }
+}
+
+impl Drop for ReaderThread {
+    fn drop(&mut self) {
+        self.stop.cancel();
+        // Unblock a reader waiting for room to send a line
+        self.lines.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn read_lines<R: BufRead>(reader: R, lines: SyncSender<LineMessage>, limits: Arc<LimitState>,
+                          stop: CancelHandle) {
+    let mut reader = LineReader::new(reader);
+    loop {
+        let message = match reader.next_line() {
+            Ok(Some((number, offset, line))) => Ok((number, offset, line.to_string())),
+            Ok(None) => return,
+            Err(e) => Err(e)
+        };
+        let end = reader.bytes_read();
+        // Once stopped, the parse may already have taken the final progress
+        if stop.is_cancelled() {
+            return;
+        }
+        limits.set_progress(reader.lines_read(), end);
+        let message = message.map(|(number, offset, line)| (number, offset, end, line));
+        let failed = message.is_err();
+        if lines.send(message).is_err() || failed {
+            return;
+        }
+    }
+}
+
+fn publish_snapshots(parsers: &[Box<LogParser>], events: &EventSink) {
+    for parser in parsers.iter() {
+        if let Some(artifact) = parser.snapshot() {
+            events.emit(|| ParseEvent::Snapshot {
+                name: parser.name().into(),
+                artifact: artifact
+            });
+        }
+    }
+}
+
+/// Parse a log that is still being written, either a live log at an HTTP
+/// URL or an uncompressed local file, until it ends. While the log grows,
+/// snapshots of the artifacts are sent to the event callback, at most once
+/// per snapshot interval. The result is the same as from
+/// parse_log_with_options for the complete log.
+pub fn follow_log(source: &str, user_agent: &str, options: &ParseOptions, follow: &FollowOptions)
+                  -> Result<Vec<(&'static str, String)>, LogParserError> {
+    let mut stats = options.parse_stats();
+    let result = follow_and_parse(source, user_agent, options, follow, &mut stats);
+    stats.finish(&result);
+    result
+}
+
+fn follow_and_parse(source: &str, user_agent: &str, options: &ParseOptions, follow: &FollowOptions,
+                    stats: &mut ParseStats) -> Result<Vec<(&'static str, String)>, LogParserError> {
+    let classifier = try!(Classifier::from_env());
+    let limits = Arc::new(LimitState::new(&options.limits));
+    let mut parsers = try!(options.create_parsers(&try!(options.normalizer())));
+
+    let stop = CancelHandle::new();
+    let live = source.starts_with("http://") || source.starts_with("https://");
+    let (download_stats, reader): (_, Box<Read + Send>) =
+        if live {
+            let mut download = try!(get_live(source, user_agent, follow.idle_timeout, &options.retry_policy,
+                                             limits.clone()));
+            download.set_stop(stop.clone());
+            (Some(download.stats()), Box::new(download))
+        } else {
+            let mut file = FollowFile::new(try!(File::open(source)), follow, limits.clone());
+            file.set_stop(stop.clone());
+            (None, Box::new(file))
+        };
+    let reader = StoppableReader {
+        inner: reader,
+        stop: stop.clone()
+    };
+    let reader = BufReader::new(LimitedReader::new(reader, limits.clone()));
+    let (sender, receiver) = sync_channel(LINE_BUFFER);
+    let reader_thread = {
+        let limits = limits.clone();
+        let reader_stop = stop.clone();
+        let handle = thread::spawn(move || read_lines(reader, sender, limits, reader_stop));
+        ReaderThread {
+            stop: stop,
+            lines: Some(receiver),
+            handle: if live { None } else { Some(handle) }
+        }
+    };
+
+    let events = match options.events {
+        Some(ref callback) => Some(EventSink::new(callback.clone())),
+        None => None
+    };
+    let prefilter = Prefilter::new(&parsers);
+    let mut failures = vec![];
+    let mut failed: ParserSet = 0;
+    let mut final_line_number = 0;
+    let mut changed = false;
+    let mut next_snapshot = Instant::now() + follow.snapshot_interval;
+    loop {
+        let now = Instant::now();
+        if now >= next_snapshot {
+            if changed {
+                if let Some(ref events) = events {
+                    publish_snapshots(&parsers, events);
+                }
+                changed = false;
+            }
+            next_snapshot = now + follow.snapshot_interval;
+        }
+        let (line_number, offset, end, line) = match reader_thread.recv_timeout(next_snapshot - now) {
+            Ok(Ok(x)) => x,
+            // The read was stopped by the limits, rather than failing
+            Ok(Err(_)) if limits.stop_reason().is_some() => break,
+            Ok(Err(e)) => {
+                if !options.best_effort {
+                    return Err(e);
+                }
+                failures.push(Failure::new("log_reader", final_line_number, &e));
+                break;
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                if limits.check_time().is_some() {
+                    break;
+                }
+                continue;
+            },
+            Err(RecvTimeoutError::Disconnected) => break
+        };
+        if limits.check_line(line_number, offset).is_some() {
+            break;
+        }
+        let matches = prefilter.matches(&line);
+        for (idx, parser) in parsers.iter_mut().enumerate() {
+            let parser_bit = 1 << idx;
+            if failed & parser_bit != 0 {
+                continue;
+            }
+            if let Err(e) = parse_line(&mut **parser, &line, line_number, offset,
+                                       matches & parser_bit != 0) {
+                if !options.best_effort {
+                    return Err(e);
+                }
+                failures.push(Failure::new(parser.name(), line_number, &e));
+                failed |= parser_bit;
+            }
+        }
+        final_line_number = line_number;
+        changed = true;
+        if parsers.iter().enumerate().all(
+            |(idx, x)| failed & (1 << idx) != 0 || is_complete(&**x, line_number + 1, end)) {
+            break;
+        }
+    }
+    // Stop the reader, so that the progress it records is final
+    drop(reader_thread);
+
+    let (lines, bytes) = limits.progress();
+    stats.lines = lines as u64;
+    stats.bytes = bytes;
+    let finished = finish_all(&mut parsers, final_line_number);
+    let (mut rv, mut metadata) = try!(classify_and_describe(finished, failures, &classifier,
+                                                            &limits, None, stats));
+    if let Some(download_stats) = download_stats {
+        metadata.http_retries = download_stats.retries();
+        metadata.http_resumes = download_stats.resumes();
+    }
+    metadata.timings = stats.trace.as_ref().map(|x| x.spans());
+    rv.push((metadata.name(), metadata.get_artifact()));
+    Ok(rv)
+}
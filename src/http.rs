@@ -1,24 +1,602 @@
-use hyper::client::{Client, Response};
-use hyper::header::UserAgent;
-use hyper::status::StatusCode;
+use hyper::client::{Client, RedirectPolicy, Response};
+use hyper::header::{ByteRangeSpec, ContentLength, ETag, EntityTag, Headers, HttpDate, IfModifiedSince,
+                    IfNoneMatch, IfRange, LastModified, Location, Range, UserAgent};
+use hyper::status::{StatusClass, StatusCode};
 use flate2::read::GzDecoder;
-use std::io::BufReader;
+use std::cmp;
+use std::io::{self, BufReader, Read};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 use std::time::Duration;
+use limits::{CancelHandle, LimitState};
 use logparser::LogParserError;
+use url::Url;
 
-pub fn get(url: &str, user_agent: &str, timeout: Option<Duration>) -> Result<Response, LogParserError> {
+// How often to check for cancellation while waiting to retry
+static SLEEP_CHECK_MILLIS: u64 = 100;
+// Longest chain of redirects followed when they are checked
+static MAX_REDIRECTS: u32 = 10;
+
+/// Checks a URL that a request is redirected to, giving the reason if it
+/// mustn't be followed.
+pub type RedirectCheck = Arc<Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// How to retry requests that fail with a network error or a 5xx status,
+/// and which redirects to follow.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// If set, each redirect is only followed if its target passes the
+    /// check; otherwise all are followed
+    pub check_redirect: Option<RedirectCheck>
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::new(30, 0),
+            check_redirect: None
+        }
+    }
+
+    pub fn no_retries() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            initial_backoff: Duration::new(0, 0),
+            max_backoff: Duration::new(0, 0),
+            check_redirect: None
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1 << cmp::min(attempt, 16);
+        cmp::min(self.initial_backoff * factor, self.max_backoff)
+    }
+}
+
+/// Counts of retried requests and resumed downloads.
+pub struct DownloadStats {
+    retries: AtomicUsize,
+    resumes: AtomicUsize
+}
+
+impl DownloadStats {
+    fn new() -> DownloadStats {
+        DownloadStats {
+            retries: AtomicUsize::new(0),
+            resumes: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries.load(Ordering::Relaxed) as u32
+    }
+
+    pub fn resumes(&self) -> u32 {
+        self.resumes.load(Ordering::Relaxed) as u32
+    }
+}
+
+/// The ETag and Last-Modified headers of a response, used to ask the server
+/// whether the log has changed since.
+#[derive(Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>
+}
+
+impl Validators {
+    pub fn new() -> Validators {
+        Validators {
+            etag: None,
+            last_modified: None
+        }
+    }
+
+    fn from_headers(headers: &Headers) -> Validators {
+        Validators {
+            etag: headers.get::<ETag>().map(|x| x.0.to_string()),
+            last_modified: headers.get::<LastModified>().map(|x| x.0.to_string())
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// The If-Range header asking for the rest of this version of the log.
+    /// Only a strong ETag can be used, so otherwise the date is.
+    fn if_range(&self) -> Option<IfRange> {
+        let etag = self.etag.as_ref().and_then(|x| x.parse::<EntityTag>().ok()).and_then(
+            |x| if x.weak { None } else { Some(IfRange::EntityTag(x)) });
+        etag.or_else(|| self.last_modified.as_ref().and_then(|x| x.parse::<HttpDate>().ok())
+                     .map(IfRange::Date))
+    }
+}
+
+/// What a request asks the server to compare the log against.
+#[derive(Clone, Copy)]
+enum Condition<'a> {
+    None,
+    /// Only send the log if it has changed
+    Modified(&'a Validators),
+    /// Only send the requested range if the log hasn't changed
+    Range(&'a Validators)
+}
+
+/// A response body that resumes with a Range request if the connection
+/// drops part way through, so readers see a single uninterrupted stream.
+pub struct Download {
+    client: Client,
+    url: String,
+    user_agent: String,
+    timeout: Option<Duration>,
+    policy: RetryPolicy,
+    limits: Arc<LimitState>,
+    response: Response,
+    position: u64,
+    length: Option<u64>,
+    failures: u32,
+    stats: Arc<DownloadStats>,
+    validators: Validators
+}
+
+impl Download {
+    fn new(client: Client, url: &str, user_agent: &str, timeout: Option<Duration>,
+           policy: &RetryPolicy, limits: Arc<LimitState>, resp: Response,
+           stats: Arc<DownloadStats>) -> Download {
+        let length = resp.headers.get::<ContentLength>().map(|x| x.0);
+        let validators = Validators::from_headers(&resp.headers);
+        Download {
+            client: client,
+            url: url.into(),
+            user_agent: user_agent.into(),
+            timeout: timeout,
+            policy: policy.clone(),
+            limits: limits,
+            response: resp,
+            position: 0,
+            length: length,
+            failures: 0,
+            stats: stats,
+            validators: validators
+        }
+    }
+
+    pub fn stats(&self) -> Arc<DownloadStats> {
+        self.stats.clone()
+    }
+
+    pub fn validators(&self) -> &Validators {
+        &self.validators
+    }
+
+    fn resume(&mut self) -> Result<(), LogParserError> {
+        let condition = if self.validators.is_empty() {
+            Condition::None
+        } else {
+            Condition::Range(&self.validators)
+        };
+        let mut response = try!(send_with_retries(&mut self.client, &self.url, &self.user_agent,
+                                                  self.position, condition, self.timeout,
+                                                  &self.policy, &self.limits, &self.stats));
+        if response.status == StatusCode::Ok {
+            if !self.validators.is_empty() {
+                // The log has changed, so the rest of it won't follow on from what we have
+                return Err(LogParserError::Other("Log changed while it was being downloaded".into()));
+            }
+            // The server ignored the Range header, so skip what we already have
+            let skipped = try!(io::copy(&mut (&mut response).take(self.position), &mut io::sink()));
+            if skipped != self.position {
+                return Err(LogParserError::Other("Resumed download was too short".into()));
+            }
+        }
+        self.response = response;
+        self.stats.resumes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let error = match self.response.read(buf) {
+                Ok(0) if self.length.map(|x| self.position < x).unwrap_or(false) => {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed early")
+                },
+                Ok(len) => {
+                    self.position += len as u64;
+                    self.failures = 0;
+                    return Ok(len);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => e
+            };
+            if self.failures >= self.policy.max_retries {
+                return Err(error);
+            }
+            self.failures += 1;
+            if let Err(e) = self.resume() {
+                return Err(match e {
+                    LogParserError::Io(e) => e,
+                    e => io::Error::new(io::ErrorKind::Other, e)
+                });
+            }
+        }
+    }
+}
+
+fn send_once(client: &Client, url: &str, user_agent: &str, offset: u64,
+             condition: Condition) -> Result<Response, LogParserError> {
+    let mut request = client.get(url)
+        .header(UserAgent(user_agent.into()));
+    if offset > 0 {
+        request = request.header(Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)]));
+    }
+    match condition {
+        Condition::None => {},
+        Condition::Modified(validators) => {
+            if let Some(etag) = validators.etag.as_ref().and_then(|x| x.parse().ok()) {
+                request = request.header(IfNoneMatch::Items(vec![etag]));
+            }
+            if let Some(date) = validators.last_modified.as_ref().and_then(|x| x.parse().ok()) {
+                request = request.header(IfModifiedSince(date));
+            }
+        },
+        Condition::Range(validators) => {
+            if let Some(if_range) = validators.if_range() {
+                request = request.header(if_range);
+            }
+        }
+    }
+    Ok(try!(request.send()))
+}
+
+/// The URL a response redirects to, if it passes the check.
+fn redirect_target(url: &str, location: &str, check: &Fn(&str) -> Result<(), String>)
+                   -> Result<String, LogParserError> {
+    let target = try!(Url::parse(url).and_then(|x| x.join(location)).map_err(
+        |e| LogParserError::Other(format!("Invalid redirect to {}: {}", location, e))));
+    let target = target.to_string();
+    try!(check(&target).map_err(LogParserError::Other));
+    Ok(target)
+}
+
+fn send(client: &Client, url: &str, user_agent: &str, offset: u64,
+        condition: Condition, policy: &RetryPolicy) -> Result<Response, LogParserError> {
+    let mut url = url.to_string();
+    let mut redirects = 0;
+    // The client follows redirects itself unless they are checked
+    loop {
+        let resp = try!(send_once(client, &url, user_agent, offset, condition));
+        let redirect = resp.status.class() == StatusClass::Redirection &&
+            resp.status != StatusCode::NotModified;
+        let location = resp.headers.get::<Location>().map(|x| x.0.clone());
+        match (policy.check_redirect.as_ref(), location) {
+            (Some(check), Some(location)) if redirect => {
+                redirects += 1;
+                if redirects > MAX_REDIRECTS {
+                    return Err(LogParserError::Other(format!("Too many redirects from {}", url)));
+                }
+                url = try!(redirect_target(&url, &location, &**check));
+            },
+            _ => return match (resp.status, condition) {
+                (StatusCode::Ok, _) => Ok(resp),
+                (StatusCode::PartialContent, _) if offset > 0 => Ok(resp),
+                (StatusCode::NotModified, Condition::Modified(_)) => Ok(resp),
+                _ => Err(LogParserError::Http(resp.status))
+            }
+        }
+    }
+}
+
+/// Whether a request that failed with this error may succeed if tried again.
+pub fn is_retryable(err: &LogParserError) -> bool {
+    match *err {
+        LogParserError::Network(_) | LogParserError::Io(_) => true,
+        LogParserError::Http(status) => status.class() == StatusClass::ServerError,
+        _ => false
+    }
+}
+
+/// Fail if the parse has been cancelled or passed its deadline.
+fn check_limits(limits: &LimitState) -> Result<(), LogParserError> {
+    match limits.check_time() {
+        Some(reason) => Err(LogParserError::Other(format!("Request stopped early: {:?}", reason))),
+        None => Ok(())
+    }
+}
+
+/// The timeout for the next request, so that it can't run past the deadline.
+fn clamp_timeout(timeout: Option<Duration>, limits: &LimitState) -> Option<Duration> {
+    // A zero timeout means no timeout to the socket, so wait at least a moment
+    let remaining = limits.remaining().map(|x| cmp::max(x, Duration::from_millis(1)));
+    match (timeout, remaining) {
+        (Some(timeout), Some(remaining)) => Some(cmp::min(timeout, remaining)),
+        (timeout, remaining) => timeout.or(remaining)
+    }
+}
+
+/// Sleep before retrying, waking early to fail if the parse is cancelled
+/// or the deadline passes.
+fn sleep_within_limits(duration: Duration, limits: &LimitState) -> Result<(), LogParserError> {
+    let mut left = duration;
+    let slice = Duration::from_millis(SLEEP_CHECK_MILLIS);
+    while left > Duration::new(0, 0) {
+        try!(check_limits(limits));
+        let remaining = limits.remaining().unwrap_or(left);
+        let sleep = cmp::min(cmp::min(left, slice), remaining);
+        thread::sleep(sleep);
+        left = if left > sleep { left - sleep } else { Duration::new(0, 0) };
+    }
+    check_limits(limits)
+}
+
+fn send_with_retries(client: &mut Client, url: &str, user_agent: &str, offset: u64,
+                     condition: Condition, timeout: Option<Duration>, policy: &RetryPolicy,
+                     limits: &LimitState, stats: &DownloadStats) -> Result<Response, LogParserError> {
+    let mut attempt = 0;
+    loop {
+        try!(check_limits(limits));
+        let timeout = clamp_timeout(timeout, limits);
+        client.set_read_timeout(timeout);
+        client.set_write_timeout(timeout);
+        if policy.check_redirect.is_some() {
+            client.set_redirect_policy(RedirectPolicy::FollowNone);
+        }
+        match send(client, url, user_agent, offset, condition, policy) {
+            Ok(resp) => return Ok(resp),
+            Err(e) => {
+                if attempt >= policy.max_retries || !is_retryable(&e) {
+                    return Err(e);
+                }
+                try!(sleep_within_limits(policy.backoff(attempt), limits));
+                attempt += 1;
+                stats.retries.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Start downloading a log. The timeout applies to each read from the
+/// connection; no request or wait runs past the deadline of the limits, and
+/// cancelling them stops the request between attempts.
+pub fn get(url: &str, user_agent: &str, timeout: Option<Duration>,
+           policy: &RetryPolicy, limits: Arc<LimitState>) -> Result<Download, LogParserError> {
+    // Without any validators the server has nothing to compare against
+    let download = try!(get_conditional(url, user_agent, timeout, policy, limits, &Validators::new()));
+    download.ok_or(LogParserError::Http(StatusCode::NotModified))
+}
+
+/// Like get, but asking the server to only send the log if it has changed
+/// since the response with the given validators. Returns None if it hasn't.
+pub fn get_conditional(url: &str, user_agent: &str, timeout: Option<Duration>,
+                       policy: &RetryPolicy, limits: Arc<LimitState>, validators: &Validators)
+                       -> Result<Option<Download>, LogParserError> {
     let mut client = Client::new();
-    client.set_read_timeout(timeout);
-    let resp = try!(client.get(url)
-        .header(UserAgent(user_agent.into()))
-        .send());
-    match resp.status {
-        StatusCode::Ok => Ok(resp),
-        _ => Err(LogParserError::Http(resp.status))
+    let stats = Arc::new(DownloadStats::new());
+    let resp = try!(send_with_retries(&mut client, url, user_agent, 0, Condition::Modified(validators),
+                                      timeout, policy, &limits, &stats));
+    if resp.status == StatusCode::NotModified {
+        return Ok(None);
     }
+    Ok(Some(Download::new(client, url, user_agent, timeout, policy, limits, resp, stats)))
 }
 
-pub fn read(resp: Response) -> Result<BufReader<GzDecoder<Response>>, LogParserError> {
-    Ok(BufReader::new(try!(GzDecoder::new(resp))))
+/// A response body that is still being written, such as a live log. If the
+/// connection drops or goes quiet for longer than the read timeout, it is
+/// reopened with a Range request from where it left off. The stream ends
+/// when the server closes it cleanly.
+pub struct LiveDownload {
+    client: Client,
+    url: String,
+    user_agent: String,
+    timeout: Option<Duration>,
+    policy: RetryPolicy,
+    limits: Arc<LimitState>,
+    response: Option<Response>,
+    position: u64,
+    failures: u32,
+    stats: Arc<DownloadStats>,
+    stop: Option<CancelHandle>
 }
 
+impl LiveDownload {
+    pub fn stats(&self) -> Arc<DownloadStats> {
+        self.stats.clone()
+    }
+
+    /// End the log once the handle is cancelled, rather than reconnecting.
+    /// A read already waiting on the connection still runs to its timeout.
+    pub fn set_stop(&mut self, stop: CancelHandle) {
+        self.stop = Some(stop);
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.as_ref().map(|x| x.is_cancelled()).unwrap_or(false)
+    }
+
+    fn reconnect(&mut self) -> Result<(), LogParserError> {
+        self.response = None;
+        let mut response = match send_with_retries(&mut self.client, &self.url, &self.user_agent,
+                                                   self.position, Condition::None, self.timeout,
+                                                   &self.policy, &self.limits, &self.stats) {
+            Ok(x) => x,
+            // Nothing has been written since
+            Err(LogParserError::Http(StatusCode::RangeNotSatisfiable)) => return Ok(()),
+            Err(e) => return Err(e)
+        };
+        if response.status == StatusCode::Ok {
+            let skipped = try!(io::copy(&mut (&mut response).take(self.position), &mut io::sink()));
+            if skipped != self.position {
+                return Err(LogParserError::Other("Live log got shorter".into()));
+            }
+        }
+        self.response = Some(response);
+        self.stats.resumes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Read for LiveDownload {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.stopped() {
+                return Ok(0);
+            }
+            let error = match self.response.as_mut().map(|x| x.read(buf)) {
+                Some(Ok(len)) => {
+                    self.position += len as u64;
+                    self.failures = 0;
+                    return Ok(len);
+                },
+                Some(Err(ref e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Some(Err(e)) => e,
+                None => io::Error::new(io::ErrorKind::UnexpectedEof, "Live log has no new data")
+            };
+            if self.failures >= self.policy.max_retries {
+                return Err(error);
+            }
+            self.failures += 1;
+            let reconnected = sleep_within_limits(self.policy.backoff(self.failures - 1), &self.limits)
+                .and_then(|_| if self.stopped() { Ok(()) } else { self.reconnect() });
+            if let Err(e) = reconnected {
+                return Err(match e {
+                    LogParserError::Io(e) => e,
+                    e => io::Error::new(io::ErrorKind::Other, e)
+                });
+            }
+        }
+    }
+}
+
+/// Start reading a live log. The timeout is how long the stream may go
+/// without any data before reconnecting. Like get, requests stop at the
+/// deadline of the limits or when they are cancelled.
+pub fn get_live(url: &str, user_agent: &str, timeout: Option<Duration>,
+                policy: &RetryPolicy, limits: Arc<LimitState>) -> Result<LiveDownload, LogParserError> {
+    let mut client = Client::new();
+    let stats = Arc::new(DownloadStats::new());
+    let resp = try!(send_with_retries(&mut client, url, user_agent, 0, Condition::None, timeout,
+                                      policy, &limits, &stats));
+    Ok(LiveDownload {
+        client: client,
+        url: url.into(),
+        user_agent: user_agent.into(),
+        timeout: timeout,
+        policy: policy.clone(),
+        limits: limits,
+        response: Some(resp),
+        position: 0,
+        failures: 0,
+        stats: stats,
+        stop: None
+    })
+}
+
+/// Where a truncated or corrupt stream ended, in decompressed bytes.
+pub struct Truncation {
+    offset: Mutex<Option<u64>>
+}
+
+impl Truncation {
+    fn new() -> Truncation {
+        Truncation {
+            offset: Mutex::new(None)
+        }
+    }
+
+    /// The offset just after the last complete line, if the stream was truncated.
+    pub fn offset(&self) -> Option<u64> {
+        *self.offset.lock().unwrap()
+    }
+}
+
+/// Reader that, when tolerant, turns a truncated or corrupt stream, as
+/// reported by the decoder with an invalid data, invalid input or
+/// unexpected end of file error, into the end of the stream, dropping any
+/// incomplete final line. The position of the error is recorded in the
+/// Truncation. Other errors, such as from the connection, are returned.
+pub struct TolerantReader<R> {
+    inner: R,
+    tolerant: bool,
+    pending: Vec<u8>,
+    ready: usize,
+    delivered: u64,
+    eof: bool,
+    truncation: Arc<Truncation>
+}
+
+impl<R: Read> TolerantReader<R> {
+    pub fn new(inner: R, tolerant: bool) -> TolerantReader<R> {
+        TolerantReader {
+            inner: inner,
+            tolerant: tolerant,
+            pending: Vec::with_capacity(16 * 1024),
+            ready: 0,
+            delivered: 0,
+            eof: false,
+            truncation: Arc::new(Truncation::new())
+        }
+    }
+
+    pub fn truncation(&self) -> Arc<Truncation> {
+        self.truncation.clone()
+    }
+}
+
+impl<R: Read> Read for TolerantReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.tolerant {
+            return self.inner.read(buf);
+        }
+        let mut chunk = [0; 8192];
+        loop {
+            // Only hand out data up to the end of the last complete line
+            if self.ready > 0 {
+                let len = cmp::min(buf.len(), self.ready);
+                buf[..len].copy_from_slice(&self.pending[..len]);
+                self.pending.drain(..len);
+                self.ready -= len;
+                self.delivered += len as u64;
+                return Ok(len);
+            }
+            if self.eof {
+                return Ok(0);
+            }
+            match self.inner.read(&mut chunk) {
+                Ok(0) => {
+                    self.eof = true;
+                    self.ready = self.pending.len();
+                },
+                Ok(len) => {
+                    self.pending.extend_from_slice(&chunk[..len]);
+                    if let Some(pos) = self.pending.iter().rposition(|&x| x == b'\n') {
+                        self.ready = pos + 1;
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData ||
+                    e.kind() == io::ErrorKind::InvalidInput ||
+                    e.kind() == io::ErrorKind::UnexpectedEof => {
+                    *self.truncation.offset.lock().unwrap() = Some(self.delivered);
+                    self.pending.clear();
+                    self.eof = true;
+                },
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+/// Decompress a gzipped log. If tolerant, a truncated or corrupt stream
+/// ends at the last complete line before the problem, rather than failing.
+pub fn read<R: Read>(resp: R, tolerant: bool)
+                     -> Result<BufReader<TolerantReader<GzDecoder<R>>>, LogParserError> {
+    Ok(BufReader::new(TolerantReader::new(try!(GzDecoder::new(resp)), tolerant)))
+}
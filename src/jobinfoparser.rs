@@ -0,0 +1,92 @@
+use checkpoint::decode_state;
+use logparser::{LogParser, LogParserError};
+use regex::Regex;
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+use std::mem;
+
+// The headers are all at the start of the log
+static JOB_INFO_MAX_LINES: u32 = 500;
+
+lazy_static! {
+    static ref RE_HEADER_LINE: Regex =
+        Regex::new("^(?P<key>builder|slave|starttime|results|buildid|builduid|revision): (?P<value>.*)$").unwrap();
+
+    static ref RE_TASKCLUSTER_HEADER: Regex =
+        Regex::new(r"^\[taskcluster [^\]]*\] (?P<key>Task ID|Task Group ID|Provisioner ID|Worker Group|Worker ID|Worker Type|Worker Node Type|Worker Pool|Instance Type|Public IP): (?P<value>.*)$").unwrap();
+
+    static ref RE_STEP_START: Regex =
+        Regex::new("^={9} Started ").unwrap();
+}
+
+pub struct JobInfoParser {
+    artifact: BTreeMap<String, String>,
+    complete: bool
+}
+
+impl JobInfoParser {
+    pub fn new() -> JobInfoParser {
+        JobInfoParser {
+            artifact: BTreeMap::new(),
+            complete: false
+        }
+    }
+
+    fn add(&mut self, key: &str, value: &str) {
+        let key = key.to_lowercase().replace(" ", "_");
+        // Only keep the first value if a header is repeated
+        self.artifact.entry(key).or_insert_with(|| value.trim().into());
+    }
+}
+
+impl LogParser for JobInfoParser {
+    fn name(&self) -> &'static str {
+        "job_info"
+    }
+
+    fn parse_line(&mut self, line: &str, _line_number: u32) -> Result<(), LogParserError> {
+        let trimmed = line.trim_left();
+
+        if let Some(captures) = RE_HEADER_LINE.captures(trimmed)
+            .or_else(|| RE_TASKCLUSTER_HEADER.captures(trimmed)) {
+            self.add(&captures["key"], &captures["value"]);
+        } else if RE_STEP_START.is_match(trimmed) {
+            // All the headers come before the first step
+            self.complete = true;
+        }
+        Ok(())
+    }
+
+    fn prefilter_literals(&self) -> Option<&'static [&'static str]> {
+        Some(&["builder: ", "slave: ", "starttime: ", "results: ", "buildid: ", "builduid: ",
+               "revision: ", "[taskcluster ", "========="])
+    }
+
+    fn line_limit(&self) -> Option<u32> {
+        Some(JOB_INFO_MAX_LINES)
+    }
+
+    fn has_artifact(&self) -> bool {
+        self.artifact.len() > 0
+    }
+
+    fn get_artifact(&mut self) -> String {
+        let artifact = mem::replace(&mut self.artifact, BTreeMap::new());
+        json::encode(&artifact).unwrap()
+    }
+
+    fn complete(&self) -> bool {
+        self.complete
+    }
+
+    fn checkpoint(&self) -> Option<String> {
+        Some(json::encode(&(&self.artifact, self.complete)).unwrap())
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), LogParserError> {
+        let (artifact, complete) = try!(decode_state(self.name(), state));
+        self.artifact = artifact;
+        self.complete = complete;
+        Ok(())
+    }
+}
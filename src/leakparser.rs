@@ -0,0 +1,164 @@
+use checkpoint::decode_state;
+use logparser::{LogParser, LogParserError};
+use regex::Regex;
+use rustc_serialize::json;
+use std::mem;
+
+lazy_static! {
+    static ref RE_BLOATVIEW_HEADER: Regex =
+        Regex::new(r"== BloatView: ALL \(cumulative\) LEAK (?:AND BLOAT )?STATISTICS, (?P<process>[\w-]+) process (?P<pid>\d+)").unwrap();
+
+    static ref RE_BLOATVIEW_ROW: Regex =
+        Regex::new(r"^(?:\d+:\d+:\d+ +\w+ - +)?\s*\d+ \|(?P<class>[^|]+?)\s*\|\s*-?\d+\s+(?P<bytes>-?\d+)\|\s*-?\d+\s+(?P<objects>-?\d+)\|").unwrap();
+
+    // The column headings between the header and the first row
+    static ref RE_BLOATVIEW_HEADINGS: Regex =
+        Regex::new(r"^(?:\d+:\d+:\d+ +\w+ - +)?\s*\|").unwrap();
+
+    static ref RE_LEAKCHECK: Regex =
+        Regex::new(r"TEST-(?P<status>UNEXPECTED-FAIL|INFO) \| leakcheck(?: large)? \| (?P<process>[\w-]+)(?: process)?: (?:leaked (?P<leaked_bytes>\d+) bytes|(?P<bytes_leaked>\d+) bytes leaked)").unwrap();
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct LeakedClass {
+    name: String,
+    objects: i64,
+    bytes: i64
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct LeakReport {
+    process: String,
+    pid: Option<u32>,
+    total_bytes: i64,
+    failed: bool,
+    linenumber: u32,
+    leaked_classes: Vec<LeakedClass>
+}
+
+impl LeakReport {
+    fn new<S>(process: S, pid: Option<u32>, line_number: u32) -> LeakReport
+        where S: Into<String> {
+        LeakReport {
+            process: process.into(),
+            pid: pid,
+            total_bytes: 0,
+            failed: false,
+            linenumber: line_number,
+            leaked_classes: vec![]
+        }
+    }
+}
+
+pub struct LeakParser {
+    artifact: Vec<LeakReport>,
+    in_table: bool
+}
+
+impl LeakParser {
+    pub fn new() -> LeakParser {
+        LeakParser {
+            artifact: vec![],
+            in_table: false
+        }
+    }
+
+    /// Parse a line of a leak table, returning false if the line isn't part
+    /// of the table, which ends the table.
+    fn parse_table_row(&mut self, line: &str) -> bool {
+        let captures = match RE_BLOATVIEW_ROW.captures(line) {
+            Some(x) => x,
+            None => {
+                self.in_table = RE_BLOATVIEW_HEADINGS.is_match(line);
+                return self.in_table;
+            }
+        };
+        let report = self.artifact.last_mut().expect("In a leak table with no report");
+        let name = &captures["class"];
+        let bytes = captures["bytes"].parse().unwrap_or(0);
+        let objects = captures["objects"].parse().unwrap_or(0);
+        if name == "TOTAL" {
+            report.total_bytes = bytes;
+        } else if objects > 0 {
+            report.leaked_classes.push(LeakedClass {
+                name: name.into(),
+                objects: objects,
+                bytes: bytes
+            });
+        }
+        true
+    }
+
+    fn parse_leakcheck(&mut self, line_number: u32, process: &str, failed: bool, bytes: i64) {
+        let found = self.artifact.iter().rposition(|x| x.process == process);
+        let report = match found {
+            Some(idx) => &mut self.artifact[idx],
+            None => {
+                self.artifact.push(LeakReport::new(process, None, line_number));
+                self.artifact.last_mut().unwrap()
+            }
+        };
+        report.total_bytes = bytes;
+        report.failed = report.failed || failed;
+    }
+}
+
+impl LogParser for LeakParser {
+    fn name(&self) -> &'static str {
+        "leak_data"
+    }
+
+    fn parse_line(&mut self, line: &str, line_number: u32) -> Result<(), LogParserError> {
+        if self.in_table && self.parse_table_row(line) {
+            return Ok(());
+        }
+
+        if let Some(captures) = RE_BLOATVIEW_HEADER.captures(line) {
+            self.artifact.push(LeakReport::new(&captures["process"],
+                                               captures["pid"].parse().ok(),
+                                               line_number));
+            self.in_table = true;
+        } else if let Some(captures) = RE_LEAKCHECK.captures(line) {
+            let bytes = captures.name("leaked_bytes")
+                .or(captures.name("bytes_leaked"))
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(0);
+            self.parse_leakcheck(line_number,
+                                 &captures["process"],
+                                 &captures["status"] == "UNEXPECTED-FAIL",
+                                 bytes);
+        }
+        Ok(())
+    }
+
+    fn prefilter_literals(&self) -> Option<&'static [&'static str]> {
+        // Table rows and headings all contain a |
+        Some(&["BloatView", "|", "leakcheck"])
+    }
+
+    fn skip_line(&mut self, _line: &str, _line_number: u32) -> Result<(), LogParserError> {
+        // Any line that isn't part of the table ends it
+        self.in_table = false;
+        Ok(())
+    }
+
+    fn has_artifact(&self) -> bool {
+        self.artifact.len() > 0
+    }
+
+    fn get_artifact(&mut self) -> String {
+        let artifact = mem::replace(&mut self.artifact, vec![]);
+        json::encode(&artifact).unwrap()
+    }
+
+    fn checkpoint(&self) -> Option<String> {
+        Some(json::encode(&(&self.artifact, self.in_table)).unwrap())
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), LogParserError> {
+        let (artifact, in_table) = try!(decode_state(self.name(), state));
+        self.artifact = artifact;
+        self.in_table = in_table;
+        Ok(())
+    }
+}
@@ -7,64 +7,637 @@ extern crate rustc_serialize;
 extern crate time;
 extern crate hyper;
 extern crate flate2;
+extern crate aho_corasick;
+extern crate url;
 
+pub mod amqp;
+pub mod cache;
+pub mod checkpoint;
+pub mod classifier;
+pub mod cluster;
+pub mod daemon;
+pub mod diff;
+pub mod events;
+pub mod follow;
 pub mod http;
+pub mod jobinfoparser;
+pub mod leakparser;
+pub mod limits;
+pub mod lines;
 pub mod logparser;
+pub mod metadata;
+pub mod metrics;
+pub mod normalize;
 pub mod performanceparser;
+pub mod pipeline;
+pub mod prefilter;
+pub mod reftestparser;
+pub mod service;
 pub mod stepparser;
 pub mod tinderboxparser;
+pub mod trace;
 
-use http::{get, read};
-use libc::c_char;
+use cache::ResultCache;
+use events::{EventCallback, EventSink, ParseEvent};
+use http::{get, get_conditional, read, RetryPolicy, Truncation};
+use libc::{c_char, c_uint, c_void};
+use limits::{CancelHandle, LimitedReader, LimitState, ParseLimits};
+use lines::LineReader;
 use logparser::{LogParser, LogParserError};
+use metadata::{Failure, ParseMetadata};
+use metrics::{ParseStats, TimedReader};
+use normalize::Normalizer;
+use trace::{Trace, TracedParser};
 use std::error::Error;
 use std::ffi::{CStr, CString};
-use std::io::BufRead;
+use std::io::{BufRead, BufReader, Cursor, Read};
 use std::str;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// All the parsers that are run by default, in the order their artifacts are returned.
+pub fn default_parsers() -> Vec<Box<LogParser>> {
+    parsers_with_normalizer(Arc::new(Normalizer::new()), false)
+}
+
+/// The default parsers, with error lines normalized by the given rules.
+fn parsers_with_normalizer(normalizer: Arc<Normalizer>, always_step_data: bool) -> Vec<Box<LogParser>> {
+    let mut parsers: Vec<Box<LogParser>> = Vec::with_capacity(7);
+    let mut step_parser = stepparser::StepParser::with_normalizer(normalizer);
+    step_parser.set_always_emit(always_step_data);
+    parsers.push(Box::new(step_parser));
+    parsers.push(Box::new(tinderboxparser::TinderboxParser::new()));
+    parsers.push(Box::new(performanceparser::PerformanceParser::new()));
+    parsers.push(Box::new(reftestparser::ReftestParser::new()));
+    parsers.push(Box::new(reftestparser::ReftestImageParser::new()));
+    parsers.push(Box::new(leakparser::LeakParser::new()));
+    parsers.push(Box::new(jobinfoparser::JobInfoParser::new()));
+    parsers
+}
+
+#[derive(Clone)]
+pub struct ParseOptions {
+    /// Decompress and run each parser on separate threads. The artifacts
+    /// are the same, but events from different parsers arrive in a
+    /// nondeterministic order; each parser's own events stay in order
+    pub parallel: bool,
+    pub retry_policy: RetryPolicy,
+    pub limits: ParseLimits,
+    /// Keep going when a parser or the log reader fails, returning the
+    /// artifacts parsed so far along with the failures
+    pub best_effort: bool,
+    /// Treat a truncated or corrupt gzip stream as ending at the last
+    /// complete line, rather than failing
+    pub tolerant_gzip: bool,
+    /// Reuse the results of earlier parses of logs that haven't changed. Not
+    /// used when there are events to send, as cached results have none
+    pub cache: Option<ResultCache>,
+    /// Names of the parsers to run, or None to run the default parsers
+    pub parsers: Option<Vec<String>>,
+    /// Return the step_data artifact even for a log without error lines,
+    /// for comparing the steps of logs
+    pub always_step_data: bool,
+    /// Time each part of the parse, adding the timings to the metadata
+    pub trace: bool,
+    /// Called with each step, error line, perf datum and job detail as it
+    /// is found
+    pub events: Option<EventCallback>,
+    /// Rules for normalizing error lines, or None to use the defaults plus
+    /// any rules from the LOGPARSER_NORMALIZATION_RULES file
+    pub normalizer: Option<Arc<Normalizer>>
+}
+
+impl ParseOptions {
+    pub fn new() -> ParseOptions {
+        ParseOptions {
+            parallel: false,
+            retry_policy: RetryPolicy::new(),
+            limits: ParseLimits::new(),
+            best_effort: false,
+            tolerant_gzip: false,
+            cache: None,
+            parsers: None,
+            always_step_data: false,
+            trace: false,
+            events: None,
+            normalizer: None
+        }
+    }
+
+    fn parse_stats(&self) -> ParseStats {
+        let mut stats = ParseStats::new();
+        if self.trace {
+            stats.trace = Some(Arc::new(Trace::new()));
+        }
+        stats
+    }
+
+    fn normalizer(&self) -> Result<Arc<Normalizer>, LogParserError> {
+        match self.normalizer {
+            Some(ref normalizer) => Ok(normalizer.clone()),
+            None => Ok(Arc::new(try!(Normalizer::from_env())))
+        }
+    }
+
+    fn create_parsers(&self, normalizer: &Arc<Normalizer>) -> Result<Vec<Box<LogParser>>, LogParserError> {
+        let all = parsers_with_normalizer(normalizer.clone(), self.always_step_data);
+        let mut parsers = match self.parsers {
+            Some(ref names) => {
+                for name in names.iter() {
+                    if !all.iter().any(|x| x.name() == name) {
+                        return Err(LogParserError::Other(format!("Unknown parser {}", name)));
+                    }
+                }
+                all.into_iter().filter(|x| names.iter().any(|name| name == x.name())).collect()
+            },
+            None => all
+        };
+        if let Some(ref callback) = self.events {
+            for parser in parsers.iter_mut() {
+                parser.set_events(EventSink::new(callback.clone()));
+            }
+        }
+        Ok(parsers)
+    }
+}
 
 pub fn parse_log(url: &str, user_agent: &str) -> Result<Vec<(&'static str, String)>, LogParserError> {
-    let mut rv = Vec::with_capacity(3);
+    parse_log_with_options(url, user_agent, &ParseOptions::new())
+}
+
+pub fn parse_log_with_options(url: &str, user_agent: &str, options: &ParseOptions)
+                              -> Result<Vec<(&'static str, String)>, LogParserError> {
+    let mut stats = options.parse_stats();
+    let result = fetch_and_parse(url, user_agent, options, &mut stats);
+    stats.finish(&result);
+    result
+}
 
-    let mut step_parser = stepparser::StepParser::new();
-    let mut tinderbox_parser = tinderboxparser::TinderboxParser::new();
-    let mut performance_parser = performanceparser::PerformanceParser::new();
+fn fetch_and_parse(url: &str, user_agent: &str, options: &ParseOptions, stats: &mut ParseStats)
+                   -> Result<Vec<(&'static str, String)>, LogParserError> {
+    let classifier = try!(classifier::Classifier::from_env());
+    let normalizer = try!(options.normalizer());
+    let limits = Arc::new(LimitState::new(&options.limits));
+    let parsers = try!(options.create_parsers(&normalizer));
+    let timeout = Some(Duration::new(30, 0));
 
-    let mut final_line_number = 0;
-    let http_resp = try!(get(url, user_agent, Some(Duration::new(30, 0))));
-    for (line_number, maybe_line) in try!(read(http_resp)).lines().enumerate() {
-        if let Ok(line) = maybe_line {
-            try!(parse_line(&mut step_parser, &*line, line_number as u32));
-            try!(parse_line(&mut tinderbox_parser, &*line, line_number as u32));
-            try!(parse_line(&mut performance_parser, &*line, line_number as u32));
-            final_line_number = line_number as u32;
+    // A cached result has no events to replay, so parse afresh when they're wanted
+    let result_cache = if options.events.is_some() { None } else { options.cache.as_ref() };
+    let cache_version = cache::parser_version(&parsers, &classifier, &normalizer,
+                                              options.always_step_data);
+    let cached = result_cache.and_then(|cache| {
+        let mut names: Vec<&'static str> = parsers.iter().map(|x| x.name()).collect();
+        names.push("job_classification");
+        cache.get(url, &cache_version, &names)
+    });
+    let request_start = Instant::now();
+    let download = match cached {
+        Some(cached) => {
+            match try!(get_conditional(url, user_agent, timeout, &options.retry_policy,
+                                       limits.clone(), &cached.validators)) {
+                Some(download) => download,
+                None => {
+                    stats.cached = true;
+                    stats.request_time = request_start.elapsed();
+                    let mut rv = cached.artifacts;
+                    let mut metadata = ParseMetadata::new();
+                    metadata.cached = true;
+                    if let Some(ref trace) = stats.trace {
+                        trace.record("http_fetch", Some("parse"), stats.download_time(), None);
+                        metadata.timings = Some(trace.spans());
+                    }
+                    rv.push((metadata.name(), metadata.get_artifact()));
+                    return Ok(rv);
+                }
+            }
+        },
+        None => try!(get(url, user_agent, timeout, &options.retry_policy, limits.clone()))
+    };
+    stats.request_time = request_start.elapsed();
+    let download_stats = download.stats();
+    let validators = download.validators().clone();
+    let download = TimedReader::new(LimitedReader::new(download, limits.clone()),
+                                    stats.download_reads.clone());
+    let reader = try!(read(download, options.tolerant_gzip));
+    let truncation = reader.get_ref().truncation();
+    let (mut rv, mut metadata) = try!(parse_decompressed(reader, parsers, &classifier, limits,
+                                                         options, Some(&*truncation), stats));
+    metadata.http_retries = download_stats.retries();
+    metadata.http_resumes = download_stats.resumes();
+    if metadata.complete {
+        if let Some(cache) = result_cache {
+            // A cache that can't be written to shouldn't fail the parse
+            let _ = cache.put(url, &cache_version, &validators, &rv);
         }
     }
+    if let Some(ref trace) = stats.trace {
+        trace.record("http_fetch", Some("parse"), stats.download_time(), None);
+        metadata.timings = Some(trace.spans());
+    }
+    rv.push((metadata.name(), metadata.get_artifact()));
+    Ok(rv)
+}
 
-    finish_parse(&mut step_parser, final_line_number, &mut rv);
-    finish_parse(&mut tinderbox_parser, final_line_number, &mut rv);
-    finish_parse(&mut performance_parser, final_line_number, &mut rv);
+/// Like parse_log_with_options, but for a log that has already been
+/// downloaded, for example one uploaded to the service.
+pub fn parse_bytes_with_options(data: Vec<u8>, gzipped: bool, options: &ParseOptions)
+                                -> Result<Vec<(&'static str, String)>, LogParserError> {
+    let mut stats = options.parse_stats();
+    let result = parse_bytes(data, gzipped, options, &mut stats);
+    stats.finish(&result);
+    result
+}
+
+fn parse_bytes(data: Vec<u8>, gzipped: bool, options: &ParseOptions, stats: &mut ParseStats)
+               -> Result<Vec<(&'static str, String)>, LogParserError> {
+    let classifier = try!(classifier::Classifier::from_env());
+    let limits = Arc::new(LimitState::new(&options.limits));
+    let parsers = try!(options.create_parsers(&try!(options.normalizer())));
+    let (mut rv, mut metadata) = if gzipped {
+        let reader = try!(read(Cursor::new(data), options.tolerant_gzip));
+        let truncation = reader.get_ref().truncation();
+        try!(parse_decompressed(reader, parsers, &classifier, limits, options, Some(&*truncation),
+                                stats))
+    } else {
+        try!(parse_decompressed(BufReader::new(Cursor::new(data)), parsers, &classifier, limits,
+                                options, None, stats))
+    };
+    metadata.timings = stats.trace.as_ref().map(|x| x.spans());
+    rv.push((metadata.name(), metadata.get_artifact()));
     Ok(rv)
 }
 
-fn parse_line<T: LogParser>(parser: &mut T, line: &str, line_number: u32) -> Result<(), LogParserError> {
-    if !parser.complete() {
-        try!(parser.parse_line(line, line_number as u32));
+/// Like parse_bytes_with_options, but reading the log as it arrives, for
+/// example from the body of a request, rather than holding all of it. The
+/// parse is always sequential, whatever options.parallel is.
+pub fn parse_stream_with_options<R: Read>(reader: R, gzipped: bool, options: &ParseOptions)
+                                          -> Result<Vec<(&'static str, String)>, LogParserError> {
+    let mut stats = options.parse_stats();
+    let result = parse_stream(reader, gzipped, options, &mut stats);
+    stats.finish(&result);
+    result
+}
+
+fn parse_stream<R: Read>(reader: R, gzipped: bool, options: &ParseOptions, stats: &mut ParseStats)
+                         -> Result<Vec<(&'static str, String)>, LogParserError> {
+    let classifier = try!(classifier::Classifier::from_env());
+    let limits = Arc::new(LimitState::new(&options.limits));
+    let parsers = try!(options.create_parsers(&try!(options.normalizer())));
+    // A slow writer can't hold up the parse past its deadline
+    let reader = LimitedReader::new(reader, limits.clone());
+    let (mut rv, mut metadata) = if gzipped {
+        let reader = try!(read(reader, options.tolerant_gzip));
+        let truncation = reader.get_ref().truncation();
+        try!(parse_sequential(reader, parsers, &classifier, &limits, options, Some(&*truncation),
+                              stats))
+    } else {
+        try!(parse_sequential(BufReader::new(reader), parsers, &classifier, &limits, options, None,
+                              stats))
     };
-    Ok(())
+    metadata.timings = stats.trace.as_ref().map(|x| x.spans());
+    rv.push((metadata.name(), metadata.get_artifact()));
+    Ok(rv)
 }
 
+/// Time the reading of the log and each parser, if the parse is traced.
+fn instrument<R: BufRead>(reader: R, parsers: Vec<Box<LogParser>>, stats: &ParseStats)
+                          -> (TimedReader<R>, Vec<Box<LogParser>>) {
+    match stats.trace {
+        Some(ref trace) => {
+            let parsers: Vec<Box<LogParser>> = parsers.into_iter()
+                .map(|x| Box::new(TracedParser::new(x, trace.clone())) as Box<LogParser>)
+                .collect();
+            (TimedReader::new(reader, stats.log_reads.clone()), parsers)
+        },
+        None => (TimedReader::untimed(reader), parsers)
+    }
+}
 
-fn finish_parse<T: LogParser>(parser: &mut T, final_line_number: u32,
-                              rv: &mut Vec<(&'static str, String)>) {
-    parser.finish_parse(final_line_number);
-    if parser.has_artifact() {
-        rv.push((parser.name(), parser.get_artifact()));
+/// Run the parsers and classifier over a decompressed log, returning the
+/// artifacts and the metadata describing the parse.
+fn parse_decompressed<R>(reader: R, parsers: Vec<Box<LogParser>>, classifier: &classifier::Classifier,
+                         limits: Arc<LimitState>, options: &ParseOptions,
+                         truncation: Option<&Truncation>, stats: &mut ParseStats)
+                         -> Result<(Vec<(&'static str, String)>, ParseMetadata), LogParserError>
+    where R: BufRead + Send + 'static {
+    if !options.parallel {
+        return parse_sequential(reader, parsers, classifier, &limits, options, truncation, stats);
     }
+    let (reader, parsers) = instrument(reader, parsers, stats);
+    let (mut parsers, final_line_number, failures) =
+        try!(run_reader_parallel(reader, parsers, limits.clone(), options.best_effort));
+    finish_and_describe(&mut parsers, final_line_number, failures, classifier, &limits, truncation,
+                        stats)
 }
 
+/// Like parse_decompressed, but always parsing sequentially, so that the
+/// reader isn't sent to other threads.
+fn parse_sequential<R: BufRead>(reader: R, parsers: Vec<Box<LogParser>>,
+                                classifier: &classifier::Classifier, limits: &LimitState,
+                                options: &ParseOptions, truncation: Option<&Truncation>,
+                                stats: &mut ParseStats)
+                                -> Result<(Vec<(&'static str, String)>, ParseMetadata), LogParserError> {
+    let (reader, parsers) = instrument(reader, parsers, stats);
+    let (mut parsers, final_line_number, failures) =
+        try!(run_reader(reader, parsers, limits, options.best_effort));
+    finish_and_describe(&mut parsers, final_line_number, failures, classifier, limits, truncation,
+                        stats)
+}
+
+/// Finish the parsers once the log has been read, and classify and
+/// describe the parse.
+fn finish_and_describe(parsers: &mut [Box<LogParser>], final_line_number: u32, failures: Vec<Failure>,
+                       classifier: &classifier::Classifier, limits: &LimitState,
+                       truncation: Option<&Truncation>, stats: &mut ParseStats)
+                       -> Result<(Vec<(&'static str, String)>, ParseMetadata), LogParserError> {
+    let finished = finish_all(parsers, final_line_number);
+    let (lines, bytes) = limits.progress();
+    stats.lines = lines as u64;
+    stats.bytes = bytes;
+    if let Some(ref trace) = stats.trace {
+        // Reading the log includes waiting for the download
+        let decompress = stats.read_time().checked_sub(stats.download_time() - stats.request_time)
+            .unwrap_or(Duration::new(0, 0));
+        trace.record("decompress", Some("parse"), decompress, None);
+    }
+    classify_and_describe(finished, failures, classifier, limits, truncation, stats)
+}
+
+/// Add the classification to the artifacts of a finished parse, and work
+/// out the metadata describing it.
+fn classify_and_describe(finished: Finished, failures: Vec<Failure>,
+                         classifier: &classifier::Classifier, limits: &LimitState,
+                         truncation: Option<&Truncation>, stats: &mut ParseStats)
+                         -> Result<(Vec<(&'static str, String)>, ParseMetadata), LogParserError> {
+    let mut rv = finished.artifacts;
+    stats.errors_truncated = finished.truncated;
+    let classification = {
+        let step_data = rv.iter().find(|x| x.0 == "step_data").map(|x| &*x.1)
+            .or(finished.steps.as_ref().map(|x| &**x));
+        match stats.trace {
+            Some(ref trace) => {
+                try!(trace.span("classify", Some("parse"), || classifier.classify(step_data)))
+            },
+            None => try!(classifier.classify(step_data))
+        }
+    };
+    rv.push(("job_classification", classification));
+
+    let mut metadata = ParseMetadata::new();
+    metadata.stop_reason = limits.stop_reason();
+    // Reaching a limit also ends the stream early, but isn't truncation
+    if metadata.stop_reason.is_none() {
+        metadata.truncated_at = truncation.and_then(|x| x.offset());
+        metadata.truncated = metadata.truncated_at.is_some();
+    }
+    metadata.complete = metadata.stop_reason.is_none() && failures.len() == 0 && !metadata.truncated;
+    metadata.failures = failures;
+    stats.truncated = metadata.truncated;
+    Ok((rv, metadata))
+}
+
+/// Run a set of parsers over every line from a reader, returning the
+/// (name, artifact) pairs for each parser with an artifact. If one of the
+/// limits is reached the artifacts are from the lines read so far.
+///
+/// In best-effort mode, a parser that fails is stopped and the others carry
+/// on, and a failure reading the log ends the parse rather than failing
+/// it. Each failure is returned alongside the artifacts.
+pub fn parse_reader<R: BufRead>(reader: R, parsers: Vec<Box<LogParser>>, limits: &LimitState,
+                                best_effort: bool)
+                                -> Result<(Vec<(&'static str, String)>, Vec<Failure>), LogParserError> {
+    let (mut parsers, final_line_number, failures) = try!(run_reader(reader, parsers, limits, best_effort));
+    Ok((finish_all(&mut parsers, final_line_number).artifacts, failures))
+}
+
+/// Run the parsers over the log for parse_reader, returning them unfinished
+/// along with the number of the last line and any failures.
+fn run_reader<R: BufRead>(reader: R, mut parsers: Vec<Box<LogParser>>, limits: &LimitState,
+                          best_effort: bool)
+                          -> Result<(Vec<Box<LogParser>>, u32, Vec<Failure>), LogParserError> {
+    let prefilter = prefilter::Prefilter::new(&parsers);
+    let mut failures = vec![];
+    let mut failed: prefilter::ParserSet = 0;
+
+    let mut lines = LineReader::new(reader);
+    let mut final_line_number = 0;
+    loop {
+        let (line_number, offset, line) = match lines.next_line() {
+            Ok(Some(x)) => x,
+            Ok(None) => break,
+            // The read was stopped by the limits, rather than failing
+            Err(_) if limits.stop_reason().is_some() => break,
+            Err(e) => {
+                if !best_effort {
+                    return Err(e);
+                }
+                failures.push(Failure::new("log_reader", final_line_number, &e));
+                break;
+            }
+        };
+        if limits.check_line(line_number, offset).is_some() {
+            break;
+        }
+        let matches = prefilter.matches(line);
+        for (idx, parser) in parsers.iter_mut().enumerate() {
+            let parser_bit = 1 << idx;
+            if failed & parser_bit != 0 {
+                continue;
+            }
+            if let Err(e) = parse_line(&mut **parser, line, line_number, offset,
+                                       matches & parser_bit != 0) {
+                if !best_effort {
+                    return Err(e);
+                }
+                failures.push(Failure::new(parser.name(), line_number, &e));
+                failed |= parser_bit;
+            }
+        }
+        final_line_number = line_number;
+        // Stop reading (and downloading) once no parser needs any more lines
+        let bytes_read = lines.bytes_read();
+        if parsers.iter().enumerate().all(
+            |(idx, x)| failed & (1 << idx) != 0 || is_complete(&**x, line_number + 1, bytes_read)) {
+            break;
+        }
+    }
+    limits.set_progress(lines.lines_read(), lines.bytes_read());
+
+    Ok((parsers, final_line_number, failures))
+}
+
+/// Like parse_reader, but decompresses and runs each parser on its own
+/// thread. The artifacts are identical to those from parse_reader.
+pub fn parse_reader_parallel<R>(reader: R, parsers: Vec<Box<LogParser>>, limits: Arc<LimitState>,
+                                best_effort: bool)
+                                -> Result<(Vec<(&'static str, String)>, Vec<Failure>), LogParserError>
+    where R: BufRead + Send + 'static {
+    let (mut parsers, final_line_number, failures) =
+        try!(run_reader_parallel(reader, parsers, limits, best_effort));
+    Ok((finish_all(&mut parsers, final_line_number).artifacts, failures))
+}
+
+fn run_reader_parallel<R>(reader: R, parsers: Vec<Box<LogParser>>, limits: Arc<LimitState>,
+                          best_effort: bool)
+                          -> Result<(Vec<Box<LogParser>>, u32, Vec<Failure>), LogParserError>
+    where R: BufRead + Send + 'static {
+    let (parsers, final_line_number, errors) = pipeline::run(reader, parsers, limits, best_effort);
+    let mut failures = Vec::with_capacity(errors.len());
+    for error in errors.into_iter() {
+        if !best_effort {
+            return Err(error.error);
+        }
+        let source = match error.parser {
+            Some(idx) => parsers[idx].name(),
+            None => "log_reader"
+        };
+        failures.push(Failure::new(source, error.linenumber, &error.error));
+    }
+    Ok((parsers, final_line_number, failures))
+}
+
+/// The artifacts of a finished parse, and what else is known about them.
+struct Finished {
+    artifacts: Vec<(&'static str, String)>,
+    /// The step data when the step parser has no artifact because there
+    /// were no error lines, so that the classifier still sees the step results
+    steps: Option<String>,
+    /// Whether any parser left some of what it found out of its artifact
+    truncated: bool
+}
+
+/// Finish each parser, returning the artifacts.
+fn finish_all(parsers: &mut [Box<LogParser>], final_line_number: u32) -> Finished {
+    let mut rv = Vec::with_capacity(parsers.len() + 1);
+    let mut steps = None;
+    let mut truncated = false;
+    for parser in parsers.iter_mut() {
+        parser.finish_parse(final_line_number);
+        // Before get_artifact, which resets the parser
+        truncated |= parser.truncated();
+        if parser.has_artifact() {
+            rv.push((parser.name(), parser.get_artifact()));
+        } else if parser.name() == "step_data" {
+            steps = parser.snapshot();
+        }
+    }
+    Finished {
+        artifacts: rv,
+        steps: steps,
+        truncated: truncated
+    }
+}
+
+/// Check if a parser is finished, either because it says so, or because
+/// the next line is beyond the limits it declared.
+fn is_complete(parser: &LogParser, line_number: u32, offset: u64) -> bool {
+    parser.complete() ||
+        parser.line_limit().map(|x| line_number >= x).unwrap_or(false) ||
+        parser.byte_limit().map(|x| offset >= x).unwrap_or(false)
+}
+
+fn parse_line(parser: &mut LogParser, line: &str, line_number: u32, offset: u64,
+              matched: bool) -> Result<(), LogParserError> {
+    if !is_complete(parser, line_number, offset) {
+        if matched {
+            try!(parser.parse_line(line, line_number as u32));
+        } else {
+            try!(parser.skip_line(line, line_number as u32));
+        }
+    };
+    Ok(())
+}
+
+
 #[no_mangle]
 pub extern fn parse_artifacts(url_cstr: *const c_char, ua_cstr: *const c_char) -> *const c_char {
+    parse_artifacts_with_options(url_cstr, ua_cstr, ParseOptions::new())
+}
+
+/// Flags for parse_artifacts_with_limits, setting the ParseOptions of the same name.
+pub const PARSE_BEST_EFFORT: c_uint = 1;
+pub const PARSE_TOLERANT_GZIP: c_uint = 2;
+pub const PARSE_TRACE: c_uint = 4;
+
+/// Like parse_artifacts, but stopping after deadline_secs seconds (if non-zero)
+/// or when cancel_handle (if not null) is cancelled, with any of the PARSE_* flags.
+#[no_mangle]
+pub extern fn parse_artifacts_with_limits(url_cstr: *const c_char, ua_cstr: *const c_char,
+                                          deadline_secs: c_uint,
+                                          cancel_handle: *const CancelHandle,
+                                          flags: c_uint) -> *const c_char {
+    parse_artifacts_with_options(url_cstr, ua_cstr, limit_options(deadline_secs, cancel_handle, flags))
+}
+
+/// Callback for parse_artifacts_with_events, called with each event as a
+/// JSON object and the user_data pointer. The string is only valid for the
+/// duration of the call. Calls may come from any thread, but never overlap.
+pub type EventCallbackFn = extern fn(*const c_char, *mut c_void);
+
+struct UserData(*mut c_void);
+
+// The pointer is only passed back to the callback, which the caller
+// promises is safe to call from any thread
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+/// Like parse_artifacts_with_limits, but calling callback (if not null)
+/// with each event as it is found.
+#[no_mangle]
+pub extern fn parse_artifacts_with_events(url_cstr: *const c_char, ua_cstr: *const c_char,
+                                          deadline_secs: c_uint,
+                                          cancel_handle: *const CancelHandle,
+                                          flags: c_uint,
+                                          callback: Option<EventCallbackFn>,
+                                          user_data: *mut c_void) -> *const c_char {
+    let mut options = limit_options(deadline_secs, cancel_handle, flags);
+    if let Some(callback) = callback {
+        let user_data = Mutex::new(UserData(user_data));
+        options.events = Some(Arc::new(move |event: &ParseEvent| {
+            let event = CString::new(event.to_json()).unwrap();
+            let user_data = user_data.lock().unwrap();
+            callback(event.as_ptr(), user_data.0);
+        }));
+    }
+    parse_artifacts_with_options(url_cstr, ua_cstr, options)
+}
+
+fn limit_options(deadline_secs: c_uint, cancel_handle: *const CancelHandle,
+                 flags: c_uint) -> ParseOptions {
+    let mut options = ParseOptions::new();
+    options.best_effort = flags & PARSE_BEST_EFFORT != 0;
+    options.tolerant_gzip = flags & PARSE_TOLERANT_GZIP != 0;
+    options.trace = flags & PARSE_TRACE != 0;
+    if deadline_secs > 0 {
+        options.limits.deadline = Some(Duration::new(deadline_secs as u64, 0));
+    }
+    if !cancel_handle.is_null() {
+        options.limits.cancel = Some(unsafe { (*cancel_handle).clone() });
+    }
+    options
+}
+
+#[no_mangle]
+pub extern fn cancel_handle_new() -> *mut CancelHandle {
+    Box::into_raw(Box::new(CancelHandle::new()))
+}
+
+#[no_mangle]
+pub extern fn cancel_handle_cancel(cancel_handle: *const CancelHandle) {
+    if !cancel_handle.is_null() {
+        unsafe { (*cancel_handle).cancel() };
+    }
+}
+
+#[no_mangle]
+pub extern fn cancel_handle_free(cancel_handle: *mut CancelHandle) {
+    if !cancel_handle.is_null() {
+        drop(unsafe { Box::from_raw(cancel_handle) });
+    }
+}
+
+fn parse_artifacts_with_options(url_cstr: *const c_char, ua_cstr: *const c_char,
+                                options: ParseOptions) -> *const c_char {
     let url = unsafe {
         if url_cstr.is_null() {
             return CString::new("").unwrap().into_raw();
@@ -77,7 +650,9 @@ pub extern fn parse_artifacts(url_cstr: *const c_char, ua_cstr: *const c_char) -
         }
         str::from_utf8(CStr::from_ptr(ua_cstr).to_bytes()).unwrap()
     };
-    let resp = match parse_log(url, user_agent) {
+    let mut options = options;
+    options.cache = ResultCache::from_env();
+    let resp = match parse_log_with_options(url, user_agent, &options) {
         Ok(items) => {
             let item_len: usize = items.iter().map(|x| x.0.len() + x.1.len() + url.len() + 23).fold(0, |acc, x| acc + x);
             let mut buf = String::with_capacity(item_len + 3);
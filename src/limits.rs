@@ -0,0 +1,179 @@
+use rustc_serialize::{Encodable, Encoder};
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// Only check the clock every this many lines
+static DEADLINE_CHECK_LINES: u32 = 1024;
+
+/// Handle that can be used to cancel a parse from another thread.
+#[derive(Clone)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>
+}
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle {
+            cancelled: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits on the total resources used by a parse. When a limit is reached
+/// the parse stops and returns the artifacts from the lines seen so far.
+#[derive(Clone)]
+pub struct ParseLimits {
+    /// Total wall-clock time, including the download
+    pub deadline: Option<Duration>,
+    /// Number of (decompressed) bytes of log
+    pub max_bytes: Option<u64>,
+    pub max_lines: Option<u32>,
+    pub cancel: Option<CancelHandle>
+}
+
+impl ParseLimits {
+    pub fn new() -> ParseLimits {
+        ParseLimits {
+            deadline: None,
+            max_bytes: None,
+            max_lines: None,
+            cancel: None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    Deadline,
+    ByteLimit,
+    LineLimit,
+    Cancelled
+}
+
+impl Encodable for StopReason {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(match *self {
+            StopReason::Deadline => "deadline",
+            StopReason::ByteLimit => "byte_limit",
+            StopReason::LineLimit => "line_limit",
+            StopReason::Cancelled => "cancelled"
+        })
+    }
+}
+
+/// The limits for a single parse, and the reason it stopped early, if it did.
+pub struct LimitState {
+    limits: ParseLimits,
+    start: Instant,
+    stop_reason: Mutex<Option<StopReason>>,
+    lines_read: AtomicUsize,
+    bytes_read: AtomicUsize
+}
+
+impl LimitState {
+    pub fn new(limits: &ParseLimits) -> LimitState {
+        LimitState {
+            limits: limits.clone(),
+            start: Instant::now(),
+            stop_reason: Mutex::new(None),
+            lines_read: AtomicUsize::new(0),
+            bytes_read: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn unlimited() -> LimitState {
+        LimitState::new(&ParseLimits::new())
+    }
+
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        *self.stop_reason.lock().unwrap()
+    }
+
+    /// Record how much of the log was read, once reading stops.
+    pub fn set_progress(&self, lines: u32, bytes: u64) {
+        self.lines_read.store(lines as usize, Ordering::Relaxed);
+        self.bytes_read.store(bytes as usize, Ordering::Relaxed);
+    }
+
+    /// The number of lines and bytes of log read.
+    pub fn progress(&self) -> (u32, u64) {
+        (self.lines_read.load(Ordering::Relaxed) as u32, self.bytes_read.load(Ordering::Relaxed) as u64)
+    }
+
+    fn stop(&self, reason: StopReason) -> StopReason {
+        let mut stop_reason = self.stop_reason.lock().unwrap();
+        if stop_reason.is_none() {
+            *stop_reason = Some(reason);
+        }
+        reason
+    }
+
+    /// Check whether the parse has been cancelled or passed its deadline.
+    pub fn check_time(&self) -> Option<StopReason> {
+        if self.limits.cancel.as_ref().map(|x| x.is_cancelled()).unwrap_or(false) {
+            Some(self.stop(StopReason::Cancelled))
+        } else if self.limits.deadline.map(|x| self.start.elapsed() >= x).unwrap_or(false) {
+            Some(self.stop(StopReason::Deadline))
+        } else {
+            None
+        }
+    }
+
+    /// Time left before the deadline, or None if there isn't one.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.limits.deadline.map(|x| {
+            let elapsed = self.start.elapsed();
+            if elapsed >= x { Duration::new(0, 0) } else { x - elapsed }
+        })
+    }
+
+    /// Check whether the parse should stop before handling the line with
+    /// the given number, starting at the given byte offset.
+    pub fn check_line(&self, line_number: u32, offset: u64) -> Option<StopReason> {
+        if self.limits.max_lines.map(|x| line_number >= x).unwrap_or(false) {
+            Some(self.stop(StopReason::LineLimit))
+        } else if self.limits.max_bytes.map(|x| offset >= x).unwrap_or(false) {
+            Some(self.stop(StopReason::ByteLimit))
+        } else if line_number % DEADLINE_CHECK_LINES == 0 {
+            self.check_time()
+        } else {
+            None
+        }
+    }
+}
+
+/// Reader that fails once the deadline passes or the parse is cancelled,
+/// so that a slow download can't hold up the parse indefinitely. Callers
+/// should check LimitState::stop_reason to tell this apart from other errors.
+pub struct LimitedReader<R> {
+    inner: R,
+    state: Arc<LimitState>
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, state: Arc<LimitState>) -> LimitedReader<R> {
+        LimitedReader {
+            inner: inner,
+            state: state
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.state.check_time().is_some() {
+            return Err(io::Error::new(io::ErrorKind::Other, "Parse stopped early"));
+        }
+        self.inner.read(buf)
+    }
+}
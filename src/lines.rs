@@ -0,0 +1,99 @@
+use logparser::LogParserError;
+use std::io::BufRead;
+use std::str;
+
+/// Reads lines into a single reused buffer, rather than allocating a new
+/// String for each line as BufRead::lines does.
+///
+/// Line numbers match those from enumerating BufRead::lines; lines that
+/// aren't valid UTF-8 are counted but skipped.
+pub struct LineReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    next_line_number: u32,
+    bytes_read: u64,
+    complete_lines_only: bool
+}
+
+impl<R: BufRead> LineReader<R> {
+    pub fn new(reader: R) -> LineReader<R> {
+        LineReader {
+            reader: reader,
+            buf: Vec::with_capacity(1024),
+            next_line_number: 0,
+            bytes_read: 0,
+            complete_lines_only: false
+        }
+    }
+
+    /// A reader for the rest of a log, where the reader starts at the given
+    /// line number and byte offset.
+    pub fn starting_at(reader: R, line_number: u32, offset: u64) -> LineReader<R> {
+        LineReader {
+            reader: reader,
+            buf: Vec::with_capacity(1024),
+            next_line_number: line_number,
+            bytes_read: offset,
+            complete_lines_only: false
+        }
+    }
+
+    /// Treat a final line without a newline as not yet read, for input
+    /// that may still be growing.
+    pub fn set_complete_lines_only(&mut self, complete_lines_only: bool) {
+        self.complete_lines_only = complete_lines_only;
+    }
+
+    /// Total number of bytes read so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Total number of lines read so far, including any that were skipped.
+    pub fn lines_read(&self) -> u32 {
+        self.next_line_number
+    }
+
+    /// Get the next line, with its line number and the byte offset of its
+    /// start, or None at the end of the input. The line is only valid until
+    /// the next call.
+    pub fn next_line(&mut self) -> Result<Option<(u32, u64, &str)>, LogParserError> {
+        let (line_number, offset, end) = match try!(self.next_valid_line()) {
+            Some(x) => x,
+            None => return Ok(None)
+        };
+        match str::from_utf8(&self.buf[..end]) {
+            Ok(line) => Ok(Some((line_number, offset, line))),
+            Err(_) => unreachable!()
+        }
+    }
+
+    /// Read the next line that is valid UTF-8 into the buffer, returning its
+    /// line number, offset and the end of the line within the buffer. This
+    /// is separate from next_line because returning the line from inside
+    /// the loop would leave the buffer borrowed for the next time round.
+    fn next_valid_line(&mut self) -> Result<Option<(u32, u64, usize)>, LogParserError> {
+        loop {
+            self.buf.clear();
+            let len = try!(self.reader.read_until(b'\n', &mut self.buf));
+            if len == 0 || (self.complete_lines_only && self.buf[len - 1] != b'\n') {
+                return Ok(None);
+            }
+            let offset = self.bytes_read;
+            self.bytes_read += len as u64;
+            let line_number = self.next_line_number;
+            self.next_line_number += 1;
+
+            let mut end = self.buf.len();
+            if end > 0 && self.buf[end - 1] == b'\n' {
+                end -= 1;
+                if end > 0 && self.buf[end - 1] == b'\r' {
+                    end -= 1;
+                }
+            }
+            if str::from_utf8(&self.buf[..end]).is_ok() {
+                return Ok(Some((line_number, offset, end)));
+            }
+        }
+    }
+}
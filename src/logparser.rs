@@ -1,3 +1,4 @@
+use events::EventSink;
 use hyper::Error as HyperError;
 use hyper::status::StatusCode;
 use rustc_serialize::json::ParserError as JsonParserError;
@@ -5,7 +6,7 @@ use std::io::Error as IoError;
 use std::error::Error;
 use std::fmt;
 
-pub trait LogParser {
+pub trait LogParser: Send {
     fn name(&self) -> &'static str;
     fn parse_line(&mut self, line: &str, line_number: u32) -> Result<(), LogParserError>;
     fn get_artifact(&mut self) -> String;
@@ -14,6 +15,48 @@ pub trait LogParser {
         return false
     }
     fn has_artifact(&self) -> bool;
+    /// Whether the parser left some of what it found out of its artifact,
+    /// to keep the artifact to a reasonable size.
+    fn truncated(&self) -> bool {
+        false
+    }
+    /// Number of lines from the start of the log that the parser needs to see.
+    fn line_limit(&self) -> Option<u32> {
+        None
+    }
+    /// Number of (decompressed) bytes from the start of the log that the
+    /// parser needs to see.
+    fn byte_limit(&self) -> Option<u64> {
+        None
+    }
+    /// Literal strings, one of which appears in every line the parser is
+    /// interested in, or None if the parser needs to see every line. Lines
+    /// containing none of the literals are passed to skip_line rather than
+    /// parse_line.
+    fn prefilter_literals(&self) -> Option<&'static [&'static str]> {
+        None
+    }
+    /// Handle a line that the prefilter ruled out, for parsers that track
+    /// state across lines.
+    fn skip_line(&mut self, _line: &str, _line_number: u32) -> Result<(), LogParserError> {
+        Ok(())
+    }
+    /// Set where to send events as they are found, for parsers that have any.
+    fn set_events(&mut self, _events: EventSink) {}
+    /// The artifact for the lines seen so far, without finishing the parse,
+    /// for parsers that can report their progress.
+    fn snapshot(&self) -> Option<String> {
+        None
+    }
+    /// The parser's state, from which restore can carry on the parse
+    /// exactly where it left off, or None if it can't be checkpointed.
+    fn checkpoint(&self) -> Option<String> {
+        None
+    }
+    /// Carry on from a state returned by checkpoint.
+    fn restore(&mut self, _state: &str) -> Result<(), LogParserError> {
+        Err(LogParserError::Other(format!("Parser {} can't be restored from a checkpoint", self.name())))
+    }
 }
 
 #[derive(Debug)]
@@ -8,22 +8,69 @@ extern crate hyper;
 extern crate flate2;
 extern crate logparser;
 
-use logparser::parse_log;
+use logparser::cluster::{cluster_logs, ClusterOptions};
+use logparser::diff::diff_logs;
+use logparser::logparser::LogParserError;
+use logparser::{parse_log, ParseOptions};
+use rustc_serialize::json;
+use std::env;
 use std::error::Error;
+use std::process;
+
+static DEFAULT_URL: &'static str =
+    "http://archive.mozilla.org/pub/firefox/tinderbox-builds/mozilla-inbound-linux64-st-an-debug/1460667041/mozilla-inbound-linux64-st-an-debug-bm74-build1-build694.txt.gz";
+static USER_AGENT: &'static str = "Log Parser Test";
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} [parse [URL]]", program);
+    println!("       {} diff BASE NEW", program);
+    println!("       {} cluster [--similarity N] LOG...", program);
+    println!("BASE, NEW and LOG are log URLs or local files");
+    println!("--similarity is the proportion of words two error lines must share to be clustered,");
+    println!("from 0 to 1 (default 0.8)");
+    process::exit(2);
+}
+
+fn parse(url: &str) -> Result<(), LogParserError> {
+    let artifacts = try!(parse_log(url, USER_AGENT));
+    println!("Got {} artifacts", artifacts.len());
+    for &(ref parser_name, ref artifact) in artifacts.iter() {
+        println!("{} {}", parser_name, artifact);
+    }
+    Ok(())
+}
+
+fn diff(base: &str, new: &str) -> Result<(), LogParserError> {
+    let diff = try!(diff_logs(base, new, USER_AGENT, &ParseOptions::new()));
+    println!("{}", json::as_pretty_json(&diff));
+    Ok(())
+}
+
+fn cluster(args: &[String]) -> Result<(), LogParserError> {
+    let mut options = ClusterOptions::new();
+    let logs = if args.get(0).map(|x| x == "--similarity").unwrap_or(false) {
+        options.similarity = try!(args.get(1).and_then(|x| x.parse().ok()).ok_or_else(
+            || LogParserError::Other("--similarity must be a number".into())));
+        &args[2..]
+    } else {
+        args
+    };
+    let report = try!(cluster_logs(logs, USER_AGENT, &options, &ParseOptions::new()));
+    println!("{}", json::as_pretty_json(&report));
+    Ok(())
+}
 
 fn main() {
-    let url =
-        "http://archive.mozilla.org/pub/firefox/tinderbox-builds/mozilla-inbound-linux64-st-an-debug/1460667041/mozilla-inbound-linux64-st-an-debug-bm74-build1-build694.txt.gz";
-    let user_agent = "Log Parser Test";
-    match parse_log(url, user_agent) {
-        Ok(artifacts) => {
-            println!("Got {} artifacts", artifacts.len());
-            for &(ref parser_name, ref artifact) in artifacts.iter() {
-                println!("{} {}", parser_name, artifact);
-            }
-        }
-        Err(e) => {
-            println!("Got error {}", e.description());
-        }
+    let args: Vec<String> = env::args().collect();
+    let result = match args.get(1).map(|x| &**x) {
+        None => parse(DEFAULT_URL),
+        Some("parse") if args.len() <= 3 => parse(args.get(2).map(|x| &**x).unwrap_or(DEFAULT_URL)),
+        Some("diff") if args.len() == 4 => diff(&args[2], &args[3]),
+        Some("cluster") if args.len() > 2 => cluster(&args[2..]),
+        _ => usage(&args[0])
+    };
+    if let Err(e) = result {
+        println!("Got error {}", e.description());
+        process::exit(1);
     }
 }
@@ -0,0 +1,67 @@
+use limits::StopReason;
+use logparser::LogParserError;
+use rustc_serialize::json;
+use std::error::Error;
+use trace::SpanTiming;
+
+/// A parser, or the log reader, that failed part way through a best-effort parse.
+#[derive(RustcEncodable)]
+pub struct Failure {
+    pub source: &'static str,
+    pub linenumber: u32,
+    pub error: String
+}
+
+impl Failure {
+    pub fn new(source: &'static str, line_number: u32, error: &LogParserError) -> Failure {
+        Failure {
+            source: source,
+            linenumber: line_number,
+            error: format!("{}: {}", error.name(), error.description())
+        }
+    }
+}
+
+/// Information about the parse itself, rather than the log contents,
+/// returned as the parse_metadata artifact.
+#[derive(RustcEncodable)]
+pub struct ParseMetadata {
+    /// true if the artifacts are from the result cache, the log not having changed
+    pub cached: bool,
+    pub http_retries: u32,
+    pub http_resumes: u32,
+    /// false if the parse stopped before the end of the log
+    pub complete: bool,
+    pub stop_reason: Option<StopReason>,
+    pub failures: Vec<Failure>,
+    /// Set if the log was truncated or corrupt, to the offset (in
+    /// decompressed bytes) of the end of the last complete line
+    pub truncated: bool,
+    pub truncated_at: Option<u64>,
+    /// Time taken by each part of the parse, if it was traced
+    pub timings: Option<Vec<SpanTiming>>
+}
+
+impl ParseMetadata {
+    pub fn new() -> ParseMetadata {
+        ParseMetadata {
+            cached: false,
+            http_retries: 0,
+            http_resumes: 0,
+            complete: true,
+            stop_reason: None,
+            failures: vec![],
+            truncated: false,
+            truncated_at: None,
+            timings: None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        "parse_metadata"
+    }
+
+    pub fn get_artifact(&self) -> String {
+        json::encode(self).unwrap()
+    }
+}
@@ -0,0 +1,284 @@
+use logparser::LogParserError;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Read};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use trace::Trace;
+
+static DURATION_BUCKETS: &'static [f64] = &[0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+static SIZE_BUCKETS: &'static [f64] = &[1024.0, 16384.0, 131072.0, 1048576.0, 8388608.0, 67108864.0];
+
+lazy_static! {
+    static ref REGISTRY: Mutex<MetricsSnapshot> = Mutex::new(MetricsSnapshot::new());
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+#[derive(Clone, RustcEncodable)]
+pub struct HistogramSnapshot {
+    /// Upper bound of each bucket, and the number of observations up to it
+    pub buckets: Vec<(f64, u64)>,
+    pub sum: f64,
+    pub count: u64
+}
+
+impl HistogramSnapshot {
+    fn new(bounds: &[f64]) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: bounds.iter().map(|x| (*x, 0)).collect(),
+            sum: 0.0,
+            count: 0
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for bucket in self.buckets.iter_mut() {
+            if value <= bucket.0 {
+                bucket.1 += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// The metrics for every parse since the process started.
+#[derive(Clone, RustcEncodable)]
+pub struct MetricsSnapshot {
+    pub parses: u64,
+    pub cache_hits: u64,
+    /// Parses that failed, by LogParserError::name
+    pub errors: BTreeMap<String, u64>,
+    pub bytes: u64,
+    pub lines: u64,
+    /// Parses where the step parser dropped error lines beyond its limit
+    pub errors_truncated: u64,
+    /// Parses of truncated or corrupt logs
+    pub logs_truncated: u64,
+    pub parse_seconds: HistogramSnapshot,
+    /// Time spent waiting for the log to download
+    pub download_seconds: HistogramSnapshot,
+    /// Time spent other than downloading, mostly decompressing and parsing
+    pub processing_seconds: HistogramSnapshot,
+    /// Size of each artifact, by artifact name
+    pub artifact_bytes: BTreeMap<String, HistogramSnapshot>
+}
+
+impl MetricsSnapshot {
+    fn new() -> MetricsSnapshot {
+        MetricsSnapshot {
+            parses: 0,
+            cache_hits: 0,
+            errors: BTreeMap::new(),
+            bytes: 0,
+            lines: 0,
+            errors_truncated: 0,
+            logs_truncated: 0,
+            parse_seconds: HistogramSnapshot::new(DURATION_BUCKETS),
+            download_seconds: HistogramSnapshot::new(DURATION_BUCKETS),
+            processing_seconds: HistogramSnapshot::new(DURATION_BUCKETS),
+            artifact_bytes: BTreeMap::new()
+        }
+    }
+
+    /// Format the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut rv = String::new();
+        counter(&mut rv, "logparser_parses_total", "Logs parsed", &[("", self.parses)]);
+        counter(&mut rv, "logparser_cache_hits_total", "Parses answered from the result cache",
+                &[("", self.cache_hits)]);
+        let errors: Vec<(String, u64)> = self.errors.iter()
+            .map(|(name, count)| (format!("{{error=\"{}\"}}", name), *count))
+            .collect();
+        counter(&mut rv, "logparser_errors_total", "Parses that failed, by error",
+                &errors.iter().map(|x| (&*x.0, x.1)).collect::<Vec<_>>());
+        counter(&mut rv, "logparser_bytes_total", "Bytes of decompressed log read", &[("", self.bytes)]);
+        counter(&mut rv, "logparser_lines_total", "Lines of log read", &[("", self.lines)]);
+        counter(&mut rv, "logparser_errors_truncated_total",
+                "Parses where error lines were dropped beyond the limit", &[("", self.errors_truncated)]);
+        counter(&mut rv, "logparser_logs_truncated_total", "Parses of truncated or corrupt logs",
+                &[("", self.logs_truncated)]);
+        histogram(&mut rv, "logparser_parse_seconds", "Total time for each parse",
+                  &[("", &self.parse_seconds)]);
+        histogram(&mut rv, "logparser_download_seconds", "Time spent waiting for each log to download",
+                  &[("", &self.download_seconds)]);
+        histogram(&mut rv, "logparser_processing_seconds",
+                  "Time spent on each parse other than downloading", &[("", &self.processing_seconds)]);
+        let artifact_labels: Vec<String> = self.artifact_bytes.keys()
+            .map(|name| format!("artifact=\"{}\"", name))
+            .collect();
+        histogram(&mut rv, "logparser_artifact_bytes", "Size of each artifact",
+                  &artifact_labels.iter().zip(self.artifact_bytes.values())
+                  .map(|(label, x)| (&**label, x)).collect::<Vec<_>>());
+        rv
+    }
+}
+
+fn counter(rv: &mut String, name: &str, help: &str, values: &[(&str, u64)]) {
+    rv.push_str(&*format!("# HELP {} {}\n# TYPE {} counter\n", name, help, name));
+    for &(labels, value) in values.iter() {
+        rv.push_str(&*format!("{}{} {}\n", name, labels, value));
+    }
+}
+
+fn histogram(rv: &mut String, name: &str, help: &str, values: &[(&str, &HistogramSnapshot)]) {
+    rv.push_str(&*format!("# HELP {} {}\n# TYPE {} histogram\n", name, help, name));
+    for &(labels, histogram) in values.iter() {
+        let separator = if labels.len() > 0 { "," } else { "" };
+        for &(bound, count) in histogram.buckets.iter() {
+            rv.push_str(&*format!("{}_bucket{{{}{}le=\"{}\"}} {}\n", name, labels, separator, bound, count));
+        }
+        rv.push_str(&*format!("{}_bucket{{{}{}le=\"+Inf\"}} {}\n", name, labels, separator, histogram.count));
+        let labels = if labels.len() > 0 { format!("{{{}}}", labels) } else { "".into() };
+        rv.push_str(&*format!("{}_sum{} {}\n", name, labels, histogram.sum));
+        rv.push_str(&*format!("{}_count{} {}\n", name, labels, histogram.count));
+    }
+}
+
+/// Get the metrics for every parse so far.
+pub fn snapshot() -> MetricsSnapshot {
+    REGISTRY.lock().unwrap().clone()
+}
+
+/// Reader that adds the time spent in each read to a total, to measure
+/// how long a parse spends waiting for the download, or decompressing.
+pub struct TimedReader<R> {
+    inner: R,
+    total: Option<Arc<Mutex<Duration>>>
+}
+
+impl<R> TimedReader<R> {
+    pub fn new(inner: R, total: Arc<Mutex<Duration>>) -> TimedReader<R> {
+        TimedReader {
+            inner: inner,
+            total: Some(total)
+        }
+    }
+
+    /// A reader that passes reads straight through.
+    pub fn untimed(inner: R) -> TimedReader<R> {
+        TimedReader {
+            inner: inner,
+            total: None
+        }
+    }
+
+    fn time<T, F: FnOnce(&mut R) -> T>(&mut self, f: F) -> T {
+        match self.total {
+            Some(ref total) => {
+                let start = Instant::now();
+                let rv = f(&mut self.inner);
+                *total.lock().unwrap() += start.elapsed();
+                rv
+            },
+            None => f(&mut self.inner)
+        }
+    }
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.time(|x| x.read(buf))
+    }
+}
+
+impl<R: BufRead> BufRead for TimedReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self.total {
+            Some(ref total) => {
+                let start = Instant::now();
+                let rv = self.inner.fill_buf();
+                *total.lock().unwrap() += start.elapsed();
+                rv
+            },
+            None => self.inner.fill_buf()
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+/// Measurements of a single parse, added to the metrics when it finishes.
+pub struct ParseStats {
+    start: Instant,
+    /// Time spent waiting for the response headers
+    pub request_time: Duration,
+    /// Time spent reading the download
+    pub download_reads: Arc<Mutex<Duration>>,
+    /// Time spent reading the decompressed log, only measured when tracing
+    pub log_reads: Arc<Mutex<Duration>>,
+    pub lines: u64,
+    pub bytes: u64,
+    pub cached: bool,
+    pub truncated: bool,
+    /// Set if a parser left some of what it found out of its artifact
+    pub errors_truncated: bool,
+    /// Timings of the parts of the parse, if it is traced
+    pub trace: Option<Arc<Trace>>
+}
+
+impl ParseStats {
+    pub fn new() -> ParseStats {
+        ParseStats {
+            start: Instant::now(),
+            request_time: Duration::new(0, 0),
+            download_reads: Arc::new(Mutex::new(Duration::new(0, 0))),
+            log_reads: Arc::new(Mutex::new(Duration::new(0, 0))),
+            lines: 0,
+            bytes: 0,
+            cached: false,
+            errors_truncated: false,
+            truncated: false,
+            trace: None
+        }
+    }
+
+    /// Total time spent downloading, including waiting for the response.
+    pub fn download_time(&self) -> Duration {
+        self.request_time + *self.download_reads.lock().unwrap()
+    }
+
+    pub fn read_time(&self) -> Duration {
+        *self.log_reads.lock().unwrap()
+    }
+
+    /// Add the parse to the metrics, given its result.
+    pub fn finish(&self, result: &Result<Vec<(&'static str, String)>, LogParserError>) {
+        let duration = self.start.elapsed();
+        let download = self.download_time();
+        let mut registry = REGISTRY.lock().unwrap();
+        registry.parses += 1;
+        registry.bytes += self.bytes;
+        registry.lines += self.lines;
+        registry.parse_seconds.observe(seconds(duration));
+        if self.cached {
+            registry.cache_hits += 1;
+        } else {
+            registry.download_seconds.observe(seconds(download));
+            registry.processing_seconds.observe(seconds(duration) - seconds(download));
+        }
+        if self.truncated {
+            registry.logs_truncated += 1;
+        }
+        match *result {
+            Ok(ref artifacts) => {
+                for &(name, ref artifact) in artifacts.iter() {
+                    registry.artifact_bytes.entry(name.into())
+                        .or_insert_with(|| HistogramSnapshot::new(SIZE_BUCKETS))
+                        .observe(artifact.len() as f64);
+                }
+                if self.errors_truncated {
+                    registry.errors_truncated += 1;
+                }
+            },
+            Err(ref e) => {
+                *registry.errors.entry(e.name().into()).or_insert(0) += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use logparser::LogParserError;
+use regex::Regex;
+use rustc_serialize::json;
+use std::env;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::Read;
+
+/// Environment variable naming a JSON file of additional normalization rules.
+pub static RULES_ENV_VAR: &'static str = "LOGPARSER_NORMALIZATION_RULES";
+
+lazy_static! {
+    // Applied in order, so more specific patterns come first
+    static ref DEFAULT_RULES: Vec<RuleSpec> = vec![
+        // References to reftest images include the line number of the image
+        RuleSpec::new(r"\breftest-image:\d+\b", "reftest-image:<IMAGE>"),
+        RuleSpec::new(r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?",
+                      "<TIMESTAMP>"),
+        RuleSpec::new(r"\b\d{1,2}:\d{2}:\d{2}(?:[.,]\d+)?\b", "<TIME>"),
+        RuleSpec::new(r"\b[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}\b",
+                      "<GUID>"),
+        RuleSpec::new(r"(?:/tmp|/var/folders|(?i:[a-z]:\\(?:Users\\[^\\]+\\AppData\\Local\\)?Temp))[/\\][^\s'\x22:]*",
+                      "<TMP>"),
+        RuleSpec::new(r"\b0x[0-9a-fA-F]+\b", "<ADDR>"),
+        RuleSpec::new(r"(?i)\b(?P<host>localhost|127\.0\.0\.1|0\.0\.0\.0|\[::1?\]):\d+\b", "$host:<PORT>"),
+        RuleSpec::new(r"(?i)\b(?P<label>port)(?P<sep>[ :=]+)\d+\b", "$label$sep<PORT>"),
+        RuleSpec::new(r"(?i)\b(?P<label>pid|process)(?P<sep>[ :=]+)\d+\b", "$label$sep<PID>"),
+        RuleSpec::new(r"\b(?P<label>[A-Z][A-Za-z]*)\(\d+\)", "$label(<PID>)")
+    ];
+
+    static ref DEFAULT_NORMALIZER: Normalizer = Normalizer::new();
+}
+
+#[derive(RustcDecodable)]
+struct RuleSpec {
+    pattern: String,
+    replacement: String
+}
+
+impl RuleSpec {
+    fn new(pattern: &str, replacement: &str) -> RuleSpec {
+        RuleSpec {
+            pattern: pattern.into(),
+            replacement: replacement.into()
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Rule {
+    regex: Regex,
+    replacement: String
+}
+
+impl Rule {
+    fn from_spec(spec: &RuleSpec) -> Result<Rule, LogParserError> {
+        Ok(Rule {
+            regex: try!(Regex::new(&spec.pattern).map_err(
+                |e| LogParserError::Other(format!("Invalid normalization pattern {}: {}",
+                                                  spec.pattern, e)))),
+            replacement: spec.replacement.clone()
+        })
+    }
+}
+
+/// Replaces the parts of error lines that differ between runs of the same
+/// failure with placeholders, and fingerprints the result.
+#[derive(Clone)]
+pub struct Normalizer {
+    rules: Vec<Rule>,
+    // The JSON of each set of added rules, to identify them in cache keys
+    added_rules: Vec<String>
+}
+
+impl Normalizer {
+    /// Create a normalizer with the default rules, which replace reftest
+    /// image references, timestamps, times, GUIDs, temporary paths,
+    /// addresses, ports and process ids.
+    pub fn new() -> Normalizer {
+        Normalizer {
+            rules: DEFAULT_RULES.iter()
+                .map(|x| Rule::from_spec(x).expect("Invalid default normalization rule"))
+                .collect(),
+            added_rules: vec![]
+        }
+    }
+
+    /// Create a normalizer with the default rules, plus any rules from the
+    /// file named in the LOGPARSER_NORMALIZATION_RULES environment variable.
+    pub fn from_env() -> Result<Normalizer, LogParserError> {
+        let mut normalizer = Normalizer::new();
+        if let Ok(path) = env::var(RULES_ENV_VAR) {
+            let mut data = String::new();
+            try!(try!(File::open(path)).read_to_string(&mut data));
+            try!(normalizer.add_rules(&data));
+        }
+        Ok(normalizer)
+    }
+
+    /// Add rules from a JSON list of objects with `pattern` and
+    /// `replacement` keys. The replacement may refer to named groups in the
+    /// pattern as `$name`. Added rules are applied before existing ones.
+    pub fn add_rules(&mut self, data: &str) -> Result<(), LogParserError> {
+        let specs: Vec<RuleSpec> = try!(json::decode(data).map_err(
+            |e| LogParserError::Other(format!("Invalid normalization rules: {}", e))));
+        let mut rules = Vec::with_capacity(specs.len() + self.rules.len());
+        for spec in specs.iter() {
+            rules.push(try!(Rule::from_spec(spec)));
+        }
+        rules.extend(self.rules.drain(..));
+        self.rules = rules;
+        self.added_rules.push(data.into());
+        Ok(())
+    }
+
+    /// Hash the rules, including the defaults, so that results can be
+    /// cached for a particular set of rules.
+    pub fn hash_rules<H: Hasher>(&self, state: &mut H) {
+        for spec in DEFAULT_RULES.iter() {
+            hash_str(&spec.pattern, state);
+            hash_str(&spec.replacement, state);
+        }
+        state.write(&u32_to_le_bytes(self.added_rules.len() as u32));
+        for rules in self.added_rules.iter() {
+            hash_str(rules, state);
+        }
+    }
+
+    /// Identify the rules, as 16 hex digits. Changing the rules changes the
+    /// normalized lines and so their fingerprints, which are only comparable
+    /// between lines normalized by rules with the same version.
+    pub fn version(&self) -> String {
+        let mut hasher = FnvHasher::new();
+        self.hash_rules(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// Trim a line and apply each rule to it in turn.
+    pub fn normalize(&self, line: &str) -> String {
+        let mut rv = line.trim().to_string();
+        for rule in self.rules.iter() {
+            rv = rule.regex.replace_all(&rv, &*rule.replacement);
+        }
+        rv
+    }
+}
+
+/// 64-bit FNV-1a. Unlike DefaultHasher, the hash of a byte string is the
+/// same across processes, platforms and compiler versions.
+pub struct FnvHasher(u64);
+
+impl FnvHasher {
+    pub fn new() -> FnvHasher {
+        FnvHasher(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes.iter() {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// The bytes of a value in little-endian order, to hash it the same way on
+/// every platform.
+pub fn u32_to_le_bytes(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+/// Hash a string as its length and bytes. Hashing a String itself is
+/// avoided, as collections of them hash their length as a usize.
+pub fn hash_str<H: Hasher>(value: &str, state: &mut H) {
+    state.write(&u32_to_le_bytes(value.len() as u32));
+    state.write(value.as_bytes());
+}
+
+/// Identify a normalized line by its FNV hash, as 16 hex digits.
+pub fn fingerprint(normalized: &str) -> String {
+    let mut hasher = FnvHasher::new();
+    hasher.write(normalized.as_bytes());
+    format!("{:016x}", hasher.finish())
+}
+
+/// Normalize a line with the default rules.
+pub fn normalize_line(line: &str) -> String {
+    DEFAULT_NORMALIZER.normalize(line)
+}
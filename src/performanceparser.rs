@@ -1,3 +1,5 @@
+use checkpoint::decode_state;
+use events::{EventSink, ParseEvent};
 use logparser::{LogParser, LogParserError};
 use regex::Regex;
 use rustc_serialize::json;
@@ -10,12 +12,14 @@ lazy_static! {
 
 pub struct PerformanceParser {
     artifact: Vec<String>,
+    events: EventSink
 }
 
 impl PerformanceParser {
     pub fn new() -> PerformanceParser {
         PerformanceParser {
             artifact: vec![],
+            events: EventSink::none()
         }
     }
 }
@@ -25,18 +29,39 @@ impl LogParser for PerformanceParser {
         "performance_data"
     }
     
-    fn parse_line(&mut self, line: &str, _line_number: u32) -> Result<(), LogParserError> {
+    fn parse_line(&mut self, line: &str, line_number: u32) -> Result<(), LogParserError> {
         if RE_PERFORMANCE.is_match(line) {
             let matches = RE_PERFORMANCE.captures(line).unwrap();
             let json_data = matches.name("data").unwrap_or("{}");
             // The rust JSON parser is spec-compliant, but the Python one is not and allows
             // NaN as a number. To work around this just push the string here and reparse
             // the result on the python side
+            self.events.emit(|| ParseEvent::PerfDatum {
+                linenumber: line_number,
+                data: json_data.into()
+            });
             self.artifact.push(json_data.into());
         };
         Ok(())
     }
 
+    fn prefilter_literals(&self) -> Option<&'static [&'static str]> {
+        Some(&["PERFHERDER_DATA"])
+    }
+
+    fn set_events(&mut self, events: EventSink) {
+        self.events = events;
+    }
+
+    fn checkpoint(&self) -> Option<String> {
+        Some(json::encode(&self.artifact).unwrap())
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), LogParserError> {
+        self.artifact = try!(decode_state(self.name(), state));
+        Ok(())
+    }
+
     fn has_artifact(&self) -> bool {
         self.artifact.len() > 0
     }
@@ -0,0 +1,205 @@
+use limits::LimitState;
+use lines::LineReader;
+use logparser::{LogParser, LogParserError};
+use prefilter::{ParserSet, Prefilter};
+use std::cmp;
+use std::io::BufRead;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+static BATCH_SIZE: usize = 4096;
+// Number of batches that can be waiting for each parser before the reader blocks
+static QUEUE_BATCHES: usize = 8;
+
+struct Line {
+    number: u32,
+    offset: u64,
+    start: usize,
+    end: usize,
+    matches: ParserSet
+}
+
+/// A set of lines stored in a single buffer.
+struct Batch {
+    text: String,
+    lines: Vec<Line>
+}
+
+impl Batch {
+    fn new() -> Batch {
+        Batch {
+            text: String::with_capacity(BATCH_SIZE * 128),
+            lines: Vec::with_capacity(BATCH_SIZE)
+        }
+    }
+
+    fn push(&mut self, number: u32, offset: u64, line: &str, matches: ParserSet) {
+        let start = self.text.len();
+        self.text.push_str(line);
+        self.lines.push(Line {
+            number: number,
+            offset: offset,
+            start: start,
+            end: self.text.len(),
+            matches: matches
+        });
+    }
+
+    fn text(&self, line: &Line) -> &str {
+        &self.text[line.start..line.end]
+    }
+}
+
+/// An error from a parser, or from reading the log if parser is None.
+pub struct LineError {
+    pub linenumber: u32,
+    pub parser: Option<usize>,
+    pub error: LogParserError
+}
+
+/// Run parsers over a reader with the reading and decompression on one
+/// thread, and each parser on its own thread, working through shared
+/// batches of lines. Returns the parsers, the number of the last line
+/// parsed and any errors, all as they would have been when parsing
+/// sequentially.
+/// Reading stops once every parser is complete, or one of the limits is
+/// reached, or, unless in best-effort mode, on the first error.
+pub fn run<R>(reader: R, parsers: Vec<Box<LogParser>>, limits: Arc<LimitState>, best_effort: bool)
+              -> (Vec<Box<LogParser>>, u32, Vec<LineError>)
+    where R: BufRead + Send + 'static {
+    let prefilter = Prefilter::new(&parsers);
+    let abort = Arc::new(AtomicBool::new(false));
+
+    let mut senders = Vec::with_capacity(parsers.len());
+    let mut workers = Vec::with_capacity(parsers.len());
+    for (idx, parser) in parsers.into_iter().enumerate() {
+        let (sender, receiver) = sync_channel(QUEUE_BATCHES);
+        let abort = if best_effort { None } else { Some(abort.clone()) };
+        senders.push(sender);
+        workers.push(thread::spawn(move || run_parser(idx, parser, receiver, abort)));
+    }
+
+    let reader_abort = abort.clone();
+    let reader_thread = thread::spawn(
+        move || read_lines(reader, prefilter, senders, reader_abort, limits));
+    let read_result = reader_thread.join().expect("Log reader thread panicked");
+
+    let mut parsers = Vec::with_capacity(workers.len());
+    let mut errors = vec![];
+    // The reader may have read lines that no parser got to, once they were
+    // complete or had failed, so this is the last line any parser was given
+    let mut final_line_number = 0;
+    for (idx, worker) in workers.into_iter().enumerate() {
+        let (parser, last_line_number, error) = worker.join().expect("Parser thread panicked");
+        final_line_number = cmp::max(final_line_number, last_line_number);
+        if let Some((line_number, error)) = error {
+            errors.push(LineError {
+                linenumber: line_number,
+                parser: Some(idx),
+                error: error
+            });
+        }
+        parsers.push(parser);
+    }
+
+    if let Err((line_number, error)) = read_result {
+        errors.push(LineError {
+            linenumber: line_number,
+            parser: None,
+            error: error
+        });
+    }
+    // A read error happens after the last line that was read, and parsers
+    // see each line in order
+    errors.sort_by_key(|x| (x.linenumber, x.parser.is_none(), x.parser));
+    (parsers, final_line_number, errors)
+}
+
+fn read_lines<R: BufRead>(reader: R, prefilter: Prefilter, senders: Vec<SyncSender<Arc<Batch>>>,
+                          abort: Arc<AtomicBool>, limits: Arc<LimitState>)
+                          -> Result<u32, (u32, LogParserError)> {
+    let mut lines = LineReader::new(reader);
+    let rv = send_lines(&mut lines, prefilter, senders, abort, &limits);
+    limits.set_progress(lines.lines_read(), lines.bytes_read());
+    rv
+}
+
+fn send_lines<R: BufRead>(lines: &mut LineReader<R>, prefilter: Prefilter,
+                          senders: Vec<SyncSender<Arc<Batch>>>, abort: Arc<AtomicBool>,
+                          limits: &LimitState) -> Result<u32, (u32, LogParserError)> {
+    let mut final_line_number = 0;
+    let mut batch = Batch::new();
+
+    loop {
+        match lines.next_line() {
+            Ok(Some((line_number, offset, line))) => {
+                if limits.check_line(line_number, offset).is_some() {
+                    break;
+                }
+                batch.push(line_number, offset, line, prefilter.matches(line));
+                final_line_number = line_number;
+            },
+            Ok(None) => break,
+            // The read was stopped by the limits, rather than failing
+            Err(_) if limits.stop_reason().is_some() => break,
+            Err(e) => {
+                send_batch(&senders, batch);
+                return Err((final_line_number, e));
+            }
+        }
+        if batch.lines.len() == BATCH_SIZE {
+            if abort.load(Ordering::Relaxed) {
+                return Ok(final_line_number);
+            }
+            if !send_batch(&senders, mem::replace(&mut batch, Batch::new())) {
+                // Every parser is complete
+                return Ok(final_line_number);
+            }
+        }
+    }
+    send_batch(&senders, batch);
+    Ok(final_line_number)
+}
+
+/// Send a batch to every parser, returning false if none of the parsers
+/// are still receiving.
+fn send_batch(senders: &[SyncSender<Arc<Batch>>], batch: Batch) -> bool {
+    if batch.lines.len() == 0 {
+        return true;
+    }
+    let batch = Arc::new(batch);
+    let mut any_sent = false;
+    for sender in senders.iter() {
+        // Parsers that failed or completed have stopped receiving
+        any_sent |= sender.send(batch.clone()).is_ok();
+    }
+    any_sent
+}
+
+/// Parse each line sent to the parser, returning it along with the number
+/// of the last line it was given and its error, if it failed.
+fn run_parser(idx: usize, mut parser: Box<LogParser>, receiver: Receiver<Arc<Batch>>,
+              abort: Option<Arc<AtomicBool>>)
+              -> (Box<LogParser>, u32, Option<(u32, LogParserError)>) {
+    let parser_bit = 1 << idx;
+    let mut last_line_number = 0;
+    for batch in receiver.iter() {
+        for line in batch.lines.iter() {
+            if ::is_complete(&*parser, line.number, line.offset) {
+                return (parser, last_line_number, None);
+            }
+            last_line_number = line.number;
+            if let Err(e) = ::parse_line(&mut *parser, batch.text(line), line.number, line.offset,
+                                         line.matches & parser_bit != 0) {
+                if let Some(ref abort) = abort {
+                    abort.store(true, Ordering::Relaxed);
+                }
+                return (parser, last_line_number, Some((line.number, e)));
+            }
+        }
+    }
+    (parser, last_line_number, None)
+}
@@ -0,0 +1,56 @@
+use aho_corasick::{AcAutomaton, Automaton};
+use logparser::LogParser;
+use std::collections::HashMap;
+
+/// Bitmask of parser indices, as passed to Prefilter::new
+pub type ParserSet = u64;
+
+/// Shared literal search across all parsers, so that each line is scanned
+/// once to find which parsers could possibly be interested in it.
+pub struct Prefilter {
+    automaton: AcAutomaton<&'static str>,
+    literal_parsers: Vec<ParserSet>,
+    unfiltered: ParserSet
+}
+
+impl Prefilter {
+    pub fn new(parsers: &[Box<LogParser>]) -> Prefilter {
+        assert!(parsers.len() <= 64, "Prefilter supports at most 64 parsers");
+        let mut literals = vec![];
+        let mut literal_parsers = vec![];
+        let mut literal_indices = HashMap::new();
+        let mut unfiltered = 0;
+
+        for (parser_idx, parser) in parsers.iter().enumerate() {
+            let bit = 1 << parser_idx;
+            match parser.prefilter_literals() {
+                Some(parser_literals) => {
+                    for literal in parser_literals.iter() {
+                        let idx = *literal_indices.entry(*literal).or_insert_with(|| {
+                            literals.push(*literal);
+                            literal_parsers.push(0);
+                            literals.len() - 1
+                        });
+                        literal_parsers[idx] |= bit;
+                    }
+                },
+                None => unfiltered |= bit
+            }
+        }
+
+        Prefilter {
+            automaton: AcAutomaton::new(literals),
+            literal_parsers: literal_parsers,
+            unfiltered: unfiltered
+        }
+    }
+
+    /// Get the set of parsers that should see a line.
+    pub fn matches(&self, line: &str) -> ParserSet {
+        let mut rv = self.unfiltered;
+        for found in self.automaton.find_overlapping(line) {
+            rv |= self.literal_parsers[found.pati];
+        }
+        rv
+    }
+}
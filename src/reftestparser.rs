@@ -0,0 +1,190 @@
+use checkpoint::decode_state;
+use logparser::{LogParser, LogParserError};
+use regex::Regex;
+use rustc_serialize::json;
+use std::mem;
+
+lazy_static! {
+    static ref RE_REFTEST_FAILURE: Regex =
+        Regex::new(
+r"REFTEST TEST-UNEXPECTED-(?P<status>FAIL|PASS) \| (?P<test>\S+)(?: (?P<op>==|!=) (?P<reference>\S+))? \| image comparison(?: \((?:==|!=)\))?, max difference: (?P<max_difference>\d+), number of differing pixels: (?P<differing_pixels>\d+)").unwrap();
+
+    static ref RE_REFTEST_IMAGE: Regex =
+        Regex::new(r"REFTEST +IMAGE(?: \d+ \((?P<role>TEST|REFERENCE)\))?: (?P<data>data:image/\w+;base64,\S*)").unwrap();
+
+    static ref RE_REFTEST_TEST_BOUNDARY: Regex =
+        Regex::new(r"REFTEST TEST-(?:START|END) ").unwrap();
+
+    // Image data, wherever it appears in the log
+    pub static ref RE_IMAGE_DATA: Regex =
+        Regex::new(r"data:image/\w+;base64,\S*").unwrap();
+}
+
+/// Identifier used to refer to the image data found on a given line,
+/// in place of the data itself. The data is in the reftest_images artifact.
+pub fn image_id(line_number: u32) -> String {
+    format!("reftest-image:{}", line_number)
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct ReftestFailure {
+    linenumber: u32,
+    status: String,
+    test: String,
+    reference: Option<String>,
+    comparison: Option<String>,
+    max_difference: u32,
+    differing_pixels: u32,
+    test_image: Option<String>,
+    reference_image: Option<String>
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct ReftestImage {
+    id: String,
+    linenumber: u32,
+    /// Each of the images on the line, in order
+    data: Vec<String>
+}
+
+pub struct ReftestParser {
+    artifact: Vec<ReftestFailure>,
+    awaiting_images: bool
+}
+
+impl ReftestParser {
+    pub fn new() -> ReftestParser {
+        ReftestParser {
+            artifact: vec![],
+            awaiting_images: false
+        }
+    }
+
+    fn add_image(&mut self, line_number: u32, role: Option<&str>) {
+        let failure = match self.artifact.last_mut() {
+            Some(x) => x,
+            None => return
+        };
+        let id = Some(image_id(line_number));
+        match role {
+            Some("REFERENCE") => failure.reference_image = id,
+            _ => failure.test_image = id
+        }
+    }
+}
+
+impl LogParser for ReftestParser {
+    fn name(&self) -> &'static str {
+        "reftest_data"
+    }
+
+    fn parse_line(&mut self, line: &str, line_number: u32) -> Result<(), LogParserError> {
+        if !line.contains("REFTEST") {
+            return Ok(());
+        }
+
+        if let Some(captures) = RE_REFTEST_FAILURE.captures(line) {
+            self.artifact.push(ReftestFailure {
+                linenumber: line_number,
+                status: captures["status"].into(),
+                test: captures["test"].into(),
+                reference: captures.name("reference").map(|x| x.into()),
+                comparison: captures.name("op").map(|x| x.into()),
+                max_difference: captures["max_difference"].parse().unwrap_or(0),
+                differing_pixels: captures["differing_pixels"].parse().unwrap_or(0),
+                test_image: None,
+                reference_image: None
+            });
+            self.awaiting_images = true;
+        } else if self.awaiting_images {
+            if let Some(captures) = RE_REFTEST_IMAGE.captures(line) {
+                self.add_image(line_number, captures.name("role"));
+            } else if RE_REFTEST_TEST_BOUNDARY.is_match(line) {
+                self.awaiting_images = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn prefilter_literals(&self) -> Option<&'static [&'static str]> {
+        Some(&["REFTEST"])
+    }
+
+    fn has_artifact(&self) -> bool {
+        self.artifact.len() > 0
+    }
+
+    fn get_artifact(&mut self) -> String {
+        let artifact = mem::replace(&mut self.artifact, vec![]);
+        json::encode(&artifact).unwrap()
+    }
+
+    fn checkpoint(&self) -> Option<String> {
+        Some(json::encode(&(&self.artifact, self.awaiting_images)).unwrap())
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), LogParserError> {
+        let (artifact, awaiting_images) = try!(decode_state(self.name(), state));
+        self.artifact = artifact;
+        self.awaiting_images = awaiting_images;
+        Ok(())
+    }
+}
+
+/// Collects the image data in the log, which the other artifacts refer to
+/// by image_id rather than including it. Every line with image data is kept,
+/// keyed by its own line number, so that the ids from reftest_data and from
+/// the error lines in step_data both resolve.
+pub struct ReftestImageParser {
+    artifact: Vec<ReftestImage>
+}
+
+impl ReftestImageParser {
+    pub fn new() -> ReftestImageParser {
+        ReftestImageParser {
+            artifact: vec![]
+        }
+    }
+}
+
+impl LogParser for ReftestImageParser {
+    fn name(&self) -> &'static str {
+        "reftest_images"
+    }
+
+    fn parse_line(&mut self, line: &str, line_number: u32) -> Result<(), LogParserError> {
+        // A line's images share an id, as the step parser replaces them all with the same one
+        let data = RE_IMAGE_DATA.find_iter(line).map(|(start, end)| line[start..end].to_string())
+            .collect::<Vec<_>>();
+        if data.len() > 0 {
+            self.artifact.push(ReftestImage {
+                id: image_id(line_number),
+                linenumber: line_number,
+                data: data
+            });
+        }
+        Ok(())
+    }
+
+    fn prefilter_literals(&self) -> Option<&'static [&'static str]> {
+        Some(&["data:image/"])
+    }
+
+    fn has_artifact(&self) -> bool {
+        self.artifact.len() > 0
+    }
+
+    fn get_artifact(&mut self) -> String {
+        let artifact = mem::replace(&mut self.artifact, vec![]);
+        json::encode(&artifact).unwrap()
+    }
+
+    fn checkpoint(&self) -> Option<String> {
+        Some(json::encode(&self.artifact).unwrap())
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), LogParserError> {
+        self.artifact = try!(decode_state(self.name(), state));
+        Ok(())
+    }
+}
@@ -1,9 +1,10 @@
 import ctypes
 import httplib
 import json
+import math
 import os
 import sys
-from ctypes import c_char_p, Structure, POINTER, c_uint32
+from ctypes import c_char_p, c_uint, c_void_p, Structure, POINTER, c_uint32, CFUNCTYPE
 
 path = os.path.split(__file__)[0]
 prefix = {'win32': ''}.get(sys.platform, 'lib')
@@ -14,22 +15,80 @@ lib = ctypes.cdll.LoadLibrary(os.path.join(path, prefix + "logparser" + extensio
 lib.parse_artifacts.argtypes = (c_char_p, c_char_p)
 lib.parse_artifacts.restype = c_char_p
 
+lib.parse_artifacts_with_limits.argtypes = (c_char_p, c_char_p, c_uint, c_void_p, c_uint)
+lib.parse_artifacts_with_limits.restype = c_char_p
+
+EVENT_CALLBACK = CFUNCTYPE(None, c_char_p, c_void_p)
+
+lib.parse_artifacts_with_events.argtypes = (c_char_p, c_char_p, c_uint, c_void_p, c_uint,
+                                            EVENT_CALLBACK, c_void_p)
+lib.parse_artifacts_with_events.restype = c_char_p
+
+lib.cancel_handle_new.argtypes = ()
+lib.cancel_handle_new.restype = c_void_p
+lib.cancel_handle_cancel.argtypes = (c_void_p,)
+lib.cancel_handle_free.argtypes = (c_void_p,)
+
 class ParserError(Exception):
     pass
 
+PARSE_BEST_EFFORT = 1
+PARSE_TOLERANT_GZIP = 2
+PARSE_TRACE = 4
+
 class ArtifactBuilderCollection(object):
-    def __init__(self, url, user_agent="Log Parser"):
+    def __init__(self, url, user_agent="Log Parser", timeout=None,
+                 best_effort=False, tolerant_gzip=False, trace=False, on_event=None):
+        """on_event, if given, is called with each event (as a dict) while
+        the log is parsed, possibly from another thread."""
         self.url = url
+        self.on_event = on_event
         self.user_agent = user_agent
+        self.timeout = timeout
+        self.flags = ((PARSE_BEST_EFFORT if best_effort else 0) |
+                      (PARSE_TOLERANT_GZIP if tolerant_gzip else 0) |
+                      (PARSE_TRACE if trace else 0))
+        self.cancel_handle = lib.cancel_handle_new()
         self.artifacts = {}
         self.key_map = {
             "job_details": ("Job Info", True),
             "step_data": ("text_log_summary", True),
-            "performance_data": ("performance_data", False)
+            "performance_data": ("performance_data", False),
+            "reftest_data": ("reftest_data", False),
+            "reftest_images": ("reftest_images", False),
+            "leak_data": ("leak_data", False),
+            "job_classification": ("job_classification", False),
+            "job_info": ("job_info", False),
+            "parse_metadata": ("parse_metadata", False)
         }
 
+    def __del__(self):
+        lib.cancel_handle_free(self.cancel_handle)
+
+    def cancel(self):
+        """Stop a parse running on another thread; the artifacts parsed
+        so far are returned."""
+        lib.cancel_handle_cancel(self.cancel_handle)
+
+    def deadline(self):
+        """The timeout in whole seconds, as the library takes it, rounded up
+        so that a short timeout isn't taken as none."""
+        if not self.timeout:
+            return 0
+        return int(math.ceil(self.timeout))
+
     def parse(self):
-        data = lib.parse_artifacts(self.url, self.user_agent)
+        if self.on_event is None:
+            data = lib.parse_artifacts_with_limits(self.url, self.user_agent,
+                                                   self.deadline(), self.cancel_handle,
+                                                   self.flags)
+        else:
+            on_event = self.on_event
+            # Keep a reference to the callback until the parse is done
+            callback = EVENT_CALLBACK(lambda event, _: on_event(json.loads(event)))
+            data = lib.parse_artifacts_with_events(self.url, self.user_agent,
+                                                   self.deadline(), self.cancel_handle,
+                                                   self.flags, callback, None)
 
         if not data:
             return
@@ -0,0 +1,320 @@
+use hyper::header::{ContentEncoding, ContentType, Encoding};
+use hyper::method::Method;
+use hyper::net::Fresh;
+use hyper::server::{Handler, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use http::RedirectCheck;
+use logparser::LogParserError;
+use metrics;
+use normalize::Normalizer;
+use rustc_serialize::json;
+use std::cmp;
+use std::error::Error;
+use std::io::{self, Read};
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use url::{form_urlencoded, Url};
+use {parse_log_with_options, parse_stream_with_options, ParseOptions};
+
+/// Number of threads that never run a parse, so that health checks and
+/// requests to reject when busy are still answered
+pub static RESERVED_THREADS: usize = 2;
+
+pub struct ServiceOptions {
+    /// Number of threads handling requests
+    pub threads: usize,
+    /// Number of parses that can run at once; further requests get a 503.
+    /// This is capped to leave some of the threads free.
+    pub max_concurrent: usize,
+    /// Deadline for requests that don't set one
+    pub default_deadline: Duration,
+    /// Longest deadline a request may set
+    pub max_deadline: Duration,
+    /// Largest log that can be uploaded
+    pub max_body_bytes: u64,
+    /// Hosts that logs may be fetched from by URL, including their
+    /// subdomains. Redirects are only followed to these hosts too.
+    pub allowed_hosts: Vec<String>,
+    /// How long a client may take to send each part of its request
+    pub read_timeout: Duration,
+    pub user_agent: String
+}
+
+impl ServiceOptions {
+    pub fn new() -> ServiceOptions {
+        ServiceOptions {
+            threads: 6,
+            max_concurrent: 4,
+            default_deadline: Duration::new(120, 0),
+            max_deadline: Duration::new(600, 0),
+            max_body_bytes: 512 * 1024 * 1024,
+            allowed_hosts: vec!["archive.mozilla.org".into(), "queue.taskcluster.net".into()],
+            read_timeout: Duration::new(30, 0),
+            user_agent: "Log Parser Service".into()
+        }
+    }
+}
+
+#[derive(RustcEncodable)]
+struct ErrorBody {
+    error: String,
+    description: String
+}
+
+#[derive(RustcEncodable)]
+struct HealthBody {
+    status: &'static str,
+    active: usize,
+    max_concurrent: usize
+}
+
+/// Parameters of a parse request, taken from the query string.
+struct ParseRequest {
+    url: Option<String>,
+    parsers: Option<Vec<String>>,
+    deadline: Option<Duration>,
+    best_effort: bool,
+    trace: bool
+}
+
+impl ParseRequest {
+    fn from_query(query: &str) -> Result<ParseRequest, String> {
+        let mut request = ParseRequest {
+            url: None,
+            parsers: None,
+            deadline: None,
+            best_effort: false,
+            trace: false
+        };
+        for (key, value) in form_urlencoded::parse(query.as_bytes()).into_iter() {
+            match &*key {
+                "url" => request.url = Some(value),
+                "parsers" => request.parsers = Some(value.split(',')
+                    .filter(|x| x.len() > 0)
+                    .map(|x| x.into())
+                    .collect()),
+                "deadline" => request.deadline = Some(Duration::new(
+                    try!(value.parse().map_err(|_| format!("Invalid deadline {}", value))), 0)),
+                "best_effort" => request.best_effort = value == "1" || value == "true",
+                "trace" => request.trace = value == "1" || value == "true",
+                _ => return Err(format!("Unknown parameter {}", key))
+            }
+        }
+        Ok(request)
+    }
+}
+
+/// Check that a log URL is on one of the allowed hosts, so that the
+/// service can't be used to make requests to internal addresses.
+fn check_url(url: &str, allowed_hosts: &[String]) -> Result<(), String> {
+    let parsed = try!(Url::parse(url).map_err(|e| format!("Invalid URL {}: {}", url, e)));
+    if parsed.scheme != "http" && parsed.scheme != "https" {
+        return Err(format!("Unsupported URL scheme {}", parsed.scheme));
+    }
+    // Addresses have no domain, so are never allowed
+    let host = match parsed.domain() {
+        Some(x) => x.to_lowercase(),
+        None => return Err(format!("Logs can't be fetched from {}", url))
+    };
+    if allowed_hosts.iter().any(|x| host == *x || host.ends_with(&*format!(".{}", x))) {
+        Ok(())
+    } else {
+        Err(format!("Logs can't be fetched from {}", host))
+    }
+}
+
+/// Reads a request body, failing once it is longer than the limit.
+struct BodyReader<R> {
+    inner: R,
+    remaining: u64,
+    too_large: bool
+}
+
+impl<R: Read> Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Read one byte past the limit to tell a body of exactly the limit from a longer one
+        let len = cmp::min(buf.len() as u64, self.remaining + 1) as usize;
+        let read = try!(self.inner.read(&mut buf[..len]));
+        if read as u64 > self.remaining {
+            self.too_large = true;
+            return Err(io::Error::new(io::ErrorKind::Other, "Log too large"));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Releases a parse slot when dropped.
+struct Slot<'a> {
+    active: &'a AtomicUsize
+}
+
+impl<'a> Drop for Slot<'a> {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct ParseService {
+    options: ServiceOptions,
+    active: AtomicUsize
+}
+
+impl ParseService {
+    fn acquire(&self) -> Option<Slot> {
+        if self.active.fetch_add(1, Ordering::SeqCst) >= self.options.max_concurrent {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Slot {
+            active: &self.active
+        })
+    }
+
+    fn health(&self) -> (StatusCode, String) {
+        (StatusCode::Ok, json::encode(&HealthBody {
+            status: "ok",
+            active: cmp::min(self.active.load(Ordering::SeqCst), self.options.max_concurrent),
+            max_concurrent: self.options.max_concurrent
+        }).unwrap())
+    }
+
+    fn parse(&self, req: Request, query: &str) -> (StatusCode, String) {
+        let request = match ParseRequest::from_query(query) {
+            Ok(x) => x,
+            Err(e) => return error_response(StatusCode::BadRequest, "BadRequest", &e)
+        };
+        if let Some(ref url) = request.url {
+            if let Err(e) = check_url(url, &self.options.allowed_hosts) {
+                return error_response(StatusCode::Forbidden, "BadRequest", &e);
+            }
+        }
+        let _slot = match self.acquire() {
+            Some(x) => x,
+            None => return error_response(StatusCode::ServiceUnavailable, "Busy",
+                                          "Too many parses in progress")
+        };
+
+        let mut options = ParseOptions::new();
+        options.parsers = request.parsers;
+        options.best_effort = request.best_effort;
+        options.trace = request.trace;
+        let deadline = request.deadline.unwrap_or(self.options.default_deadline);
+        options.limits.deadline = Some(cmp::min(deadline, self.options.max_deadline));
+        let allowed_hosts = self.options.allowed_hosts.clone();
+        options.retry_policy.check_redirect = Some(
+            Arc::new(move |url: &str| check_url(url, &allowed_hosts)) as RedirectCheck);
+        // Check the parser names the same way the parse will
+        if let Err(e) = options.create_parsers(&Arc::new(Normalizer::new())) {
+            return error_response(StatusCode::BadRequest, "BadRequest", e.description());
+        }
+
+        let result = match request.url {
+            Some(ref url) => parse_log_with_options(url, &self.options.user_agent, &options),
+            None => {
+                let gzipped = req.headers.get::<ContentEncoding>()
+                    .map(|x| x.0.contains(&Encoding::Gzip))
+                    .unwrap_or(false);
+                // The log is parsed as it is uploaded, rather than held in memory
+                let mut body = BodyReader {
+                    inner: req,
+                    remaining: self.options.max_body_bytes,
+                    too_large: false
+                };
+                let result = parse_stream_with_options(&mut body, gzipped, &options);
+                if body.too_large {
+                    return error_response(StatusCode::PayloadTooLarge, "BadRequest", "Log too large");
+                }
+                result
+            }
+        };
+
+        match result {
+            Ok(artifacts) => {
+                let mut body = String::from("{");
+                for (idx, &(name, ref artifact)) in artifacts.iter().enumerate() {
+                    if idx > 0 {
+                        body.push_str(", ");
+                    }
+                    body.push_str(&*format!("\"{}\": {}", name, artifact));
+                }
+                body.push('}');
+                (StatusCode::Ok, body)
+            },
+            Err(e) => {
+                let status = match e {
+                    // The log server, rather than the request, is at fault
+                    LogParserError::Network(_) | LogParserError::Http(_) => StatusCode::BadGateway,
+                    _ => StatusCode::InternalServerError
+                };
+                error_response(status, e.name(), e.description())
+            }
+        }
+    }
+}
+
+fn error_response(status: StatusCode, error: &str, description: &str) -> (StatusCode, String) {
+    (status, json::encode(&ErrorBody {
+        error: error.into(),
+        description: description.into()
+    }).unwrap())
+}
+
+impl Handler for ParseService {
+    fn handle(&self, req: Request, mut res: Response<Fresh>) {
+        let uri = match req.uri {
+            RequestUri::AbsolutePath(ref x) => x.clone(),
+            _ => "".into()
+        };
+        let (path, query) = match uri.find('?') {
+            Some(idx) => (&uri[..idx], &uri[idx + 1..]),
+            None => (&*uri, "")
+        };
+        let method = req.method.clone();
+        let (status, body) = match (&method, path) {
+            (&Method::Get, "/health") => self.health(),
+            (&Method::Get, "/metrics") => (StatusCode::Ok, metrics::snapshot().to_prometheus()),
+            (&Method::Post, "/parse") => self.parse(req, query),
+            (_, "/health") | (_, "/metrics") | (_, "/parse") => {
+                error_response(StatusCode::MethodNotAllowed, "BadRequest", "Method not allowed")
+            },
+            _ => error_response(StatusCode::NotFound, "BadRequest", "Not found")
+        };
+        *res.status_mut() = status;
+        if path == "/metrics" && status == StatusCode::Ok {
+            res.headers_mut().set(ContentType("text/plain; version=0.0.4".parse().unwrap()));
+        } else {
+            res.headers_mut().set(ContentType::json());
+        }
+        // The client may have gone away, and there is no one else to tell
+        let _ = res.send(body.as_bytes());
+    }
+}
+
+/// Serve parse requests over HTTP on addr. `POST /parse` parses the log
+/// at the `url` query parameter, which must be on one of the allowed hosts,
+/// or, without one, the request body (which may be gzipped with a
+/// Content-Encoding header). The `parsers`,
+/// `deadline` (in seconds), `best_effort` and `trace` parameters set the
+/// options for the parse. The artifacts are returned as a JSON object keyed by name.
+/// `GET /health` reports that the service is up, and `GET /metrics` gives
+/// the metrics for every parse in the Prometheus text format.
+pub fn serve<A: ToSocketAddrs>(addr: A, mut options: ServiceOptions) -> Result<Listening, LogParserError> {
+    if options.threads <= RESERVED_THREADS {
+        return Err(LogParserError::Other(
+            format!("The service needs more than {} threads", RESERVED_THREADS)));
+    }
+    let threads = options.threads;
+    options.max_concurrent = cmp::min(options.max_concurrent, threads - RESERVED_THREADS);
+    let mut server = try!(Server::http(addr));
+    // Otherwise a client that stops sending holds a thread, and its parse slot, for good
+    server.set_read_timeout(Some(options.read_timeout));
+    let service = ParseService {
+        options: options,
+        active: AtomicUsize::new(0)
+    };
+    Ok(try!(server.handle_threads(service, threads)))
+}
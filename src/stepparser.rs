@@ -1,13 +1,27 @@
+use checkpoint::decode_state;
 use chrono::{UTC, TimeZone};
+use events::{EventSink, ParseEvent};
 use logparser::{LogParser, LogParserError};
+use normalize::{fingerprint, Normalizer};
+use reftestparser::{image_id, RE_IMAGE_DATA};
 use regex::{Regex, RegexSet};
 use rustc_serialize::json::{self, Json, ToJson};
-use rustc_serialize::{Encodable, Encoder};
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
 use std::convert::From;
 use std::mem;
+use std::sync::Arc;
 
 static PARSER_MAX_STEP_ERROR_LINES: u8 = 100;
 
+// Every step marker or error line contains at least one of these
+static PREFILTER_LITERALS: &'static [&'static str] = &[
+    "=========", "TEST-UNEXPECTED-", "fatal error", "FATAL", "PROCESS-CRASH",
+    "Assertion fail", "###!!! ABORT:", "E/GeckoLinker", "SUMMARY: ", "Automation Error:",
+    "command timed out:", "wget: unable ", "TEST-VALGRIND-ERROR", "error: TEST FAILED",
+    ": ***", "Error", "Exception: ", "remoteFailed:", "rm: cannot ", "abort:",
+    "Output exceeded ", "The web-page 'stop build' button was pressed", ":error]",
+    ":exception]", " error(", ": error:", " error ", "ERROR", "CRITICAL"];
+
 lazy_static! {
     static ref RE_HEADER_LINE: Regex =
         Regex::new("^(?:builder|slave|starttime|results|buildid|builduid|revision): ").unwrap();
@@ -93,11 +107,9 @@ impl StepResult {
             _ => StepResult::Unknown
         }
     }
-}
 
-impl ToJson for StepResult {
-    fn to_json(&self) -> Json {
-        Json::String(match *self {
+    fn name(&self) -> &'static str {
+        match *self {
             StepResult::Unknown => "unknown",
             StepResult::Success => "success",
             StepResult::TestFailed => "testfailed",
@@ -106,60 +118,93 @@ impl ToJson for StepResult {
             StepResult::Exception => "exception",
             StepResult::Retry => "retry",
             StepResult::UserCancel => "usercancel",
-        }.into())
+        }
+    }
+}
+
+impl ToJson for StepResult {
+    fn to_json(&self) -> Json {
+        Json::String(self.name().into())
     }
 }
 
 impl Encodable for StepResult {
     fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
-        s.emit_str(match *self {
-            StepResult::Unknown => "unknown",
-            StepResult::Success => "success",
-            StepResult::TestFailed => "testfailed",
-            StepResult::Busted => "busted",
-            StepResult::Skipped => "skipped",
-            StepResult::Exception => "exception",
-            StepResult::Retry => "retry",
-            StepResult::UserCancel => "usercancel",
+        s.emit_str(self.name())
+    }
+}
+
+impl Decodable for StepResult {
+    fn decode<D: Decoder>(d: &mut D) -> Result<StepResult, D::Error> {
+        Ok(match &*try!(d.read_str()) {
+            "success" => StepResult::Success,
+            "testfailed" => StepResult::TestFailed,
+            "busted" => StepResult::Busted,
+            "skipped" => StepResult::Skipped,
+            "exception" => StepResult::Exception,
+            "retry" => StepResult::Retry,
+            "usercancel" => StepResult::UserCancel,
+            _ => StepResult::Unknown
         })
     }
 }
 
-#[derive(Debug, RustcEncodable, Clone)]
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
 struct ErrorLine {
     linenumber: u32,
-    line: String
+    line: String,
+    /// The line with the parts that differ between runs replaced
+    normalized: String,
+    /// Hash of the normalized line, to group the same failure across logs.
+    /// Only comparable between artifacts with the same normalization_version.
+    fingerprint: String
 }
 
 impl ErrorLine {
-    fn new<S>(line_number: u32, line: S) -> ErrorLine
+    fn new<S>(line_number: u32, line: S, normalizer: &Normalizer) -> ErrorLine
            where S: Into<String> {
+        let line = line.into();
+        let normalized = normalizer.normalize(&line);
         ErrorLine {
             linenumber: line_number,
-            line: line.into()
+            fingerprint: fingerprint(&normalized),
+            line: line,
+            normalized: normalized
         }
     }
 }
 
 
-#[derive(RustcEncodable)]
+#[derive(RustcEncodable, RustcDecodable)]
 struct StepData {
     steps: Vec<Step>,
     all_errors:Vec<ErrorLine>, //TODO: Try making this a reference to avoid a copy
-    errors_truncated: bool
+    errors_truncated: bool,
+    /// Version of the rules the error lines were normalized with
+    normalization_version: String
 }
 
 impl StepData {
-    fn new() -> StepData {
+    fn new(normalization_version: String) -> StepData {
         StepData {
             steps: vec![],
             all_errors: vec![],
-            errors_truncated: false
+            errors_truncated: false,
+            normalization_version: normalization_version
         }
     }
 }
 
-#[derive(RustcEncodable, Debug)]
+/// StepData including a step that hasn't finished yet.
+#[derive(RustcEncodable)]
+struct StepDataSnapshot<'a> {
+    steps: Vec<&'a Step>,
+    all_errors: Vec<&'a ErrorLine>,
+    errors_truncated: bool,
+    normalization_version: &'a str
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug)]
 struct Step {
     errors: Vec<ErrorLine>,
     name: String,
@@ -211,6 +256,7 @@ impl Step {
     }
 }
 
+#[derive(RustcEncodable, RustcDecodable)]
 enum StepState {
     AwaitingFirstStep,
     StepInProgress(Step),
@@ -230,23 +276,44 @@ pub struct StepParser {
     artifact: StepData,
     state: StepState,
     step_number: u32,
+    events: EventSink,
+    normalizer: Arc<Normalizer>,
+    always_emit: bool
 }
 
 impl StepParser {
     pub fn new() -> StepParser {
+        StepParser::with_normalizer(Arc::new(Normalizer::new()))
+    }
+
+    /// Create a parser that normalizes error lines with the given rules.
+    pub fn with_normalizer(normalizer: Arc<Normalizer>) -> StepParser {
         StepParser {
-            artifact: StepData::new(),
+            artifact: StepData::new(normalizer.version()),
             state: StepState::AwaitingFirstStep,
-            step_number: 0
+            step_number: 0,
+            events: EventSink::none(),
+            normalizer: normalizer,
+            always_emit: false
         }
     }
 
+    /// Return the artifact even for a log without any error lines.
+    pub fn set_always_emit(&mut self, always_emit: bool) {
+        self.always_emit = always_emit;
+    }
+
     fn start_step(&mut self,
                   line_number: u32,
                   name: Option<&str>,
                   timestamp: Option<&str>) {
-        self.state = StepState::StepInProgress(
-            Step::new(name.unwrap_or("Unnamed step"), timestamp, line_number, self.step_number));
+        let step = Step::new(name.unwrap_or("Unnamed step"), timestamp, line_number, self.step_number);
+        self.events.emit(|| ParseEvent::StepStarted {
+            name: step.name.clone(),
+            linenumber: line_number,
+            started: step.started.clone()
+        });
+        self.state = StepState::StepInProgress(step);
         self.step_number += 1;
     }
 
@@ -256,15 +323,20 @@ impl StepParser {
                 result_code: Option<&str>) {
         let mut step = mem::replace(&mut self.state, StepState::StepFinished).unwrap();
 
-        step.error_count = step.errors.len() as u32;
         step.finished_linenumber = line_number;
         step.finished = timestamp.map(|x| x.into());
         if let Some(code) = result_code {
             step.result = StepResult::from_str(code);
         }
         step.duration = step.calculate_duration();
+        self.events.emit(|| ParseEvent::StepFinished {
+            name: step.name.clone(),
+            linenumber: line_number,
+            result: step.result.name().into(),
+            error_count: step.error_count,
+            duration: step.duration
+        });
         if step.error_count > PARSER_MAX_STEP_ERROR_LINES as u32 {
-            step.errors.truncate(PARSER_MAX_STEP_ERROR_LINES as usize);
             self.artifact.errors_truncated =  true
         }
         self.artifact.all_errors.extend(step.errors.iter().map(|x| x.clone()));
@@ -280,7 +352,25 @@ impl StepParser {
         //TODO: maybe copy the sub-parser design?
         if self.is_error_line(trimmed) {
             if let StepState::StepInProgress(ref mut state) = self.state {
-                state.errors.push(ErrorLine::new(line_number, line));
+                state.error_count += 1;
+                // Lines past the limit are only counted
+                if state.errors.len() >= PARSER_MAX_STEP_ERROR_LINES as usize {
+                    return;
+                }
+                // Reftest image data is huge; keep a reference to it rather than the data
+                if RE_IMAGE_DATA.is_match(line) {
+                    let line = RE_IMAGE_DATA.replace_all(line, &*image_id(line_number));
+                    state.errors.push(ErrorLine::new(line_number, line, &self.normalizer));
+                } else {
+                    state.errors.push(ErrorLine::new(line_number, line, &self.normalizer));
+                }
+                let error = &state.errors[state.errors.len() - 1];
+                self.events.emit(|| ParseEvent::ErrorLine {
+                    step: state.name.clone(),
+                    linenumber: error.linenumber,
+                    line: error.line.clone(),
+                    fingerprint: error.fingerprint.clone()
+                });
             }
         }
     }
@@ -354,6 +444,18 @@ impl LogParser for StepParser {
         Ok(())
     }
 
+    fn prefilter_literals(&self) -> Option<&'static [&'static str]> {
+        Some(PREFILTER_LITERALS)
+    }
+
+    fn skip_line(&mut self, line: &str, line_number: u32) -> Result<(), LogParserError> {
+        // Any line may start an unnamed step, so only skip when a step is running
+        match self.state {
+            StepState::StepInProgress(_) => Ok(()),
+            _ => self.parse_line(line, line_number)
+        }
+    }
+
     fn finish_parse(&mut self, last_line_number: u32) {
         match self.state {
             StepState::StepInProgress(_) => self.end_step(last_line_number, None, None),
@@ -361,12 +463,63 @@ impl LogParser for StepParser {
         }
     }
 
+    fn set_events(&mut self, events: EventSink) {
+        self.events = events;
+    }
+
+    fn snapshot(&self) -> Option<String> {
+        // The running step, as it would be if it finished now with an unknown result
+        let running = match self.state {
+            StepState::StepInProgress(ref step) => Some(Step {
+                errors: step.errors.clone(),
+                name: step.name.clone(),
+                started: step.started.clone(),
+                started_linenumber: step.started_linenumber,
+                finished_linenumber: 0,
+                finished: None,
+                result: StepResult::Unknown,
+                error_count: step.error_count,
+                duration: None,
+                order: step.order
+            }),
+            _ => None
+        };
+        let mut snapshot = StepDataSnapshot {
+            steps: self.artifact.steps.iter().collect(),
+            all_errors: self.artifact.all_errors.iter().collect(),
+            errors_truncated: self.artifact.errors_truncated,
+            normalization_version: &self.artifact.normalization_version
+        };
+        if let Some(ref step) = running {
+            snapshot.steps.push(step);
+            snapshot.all_errors.extend(step.errors.iter());
+            snapshot.errors_truncated |= step.error_count > PARSER_MAX_STEP_ERROR_LINES as u32;
+        }
+        Some(json::encode(&snapshot).unwrap())
+    }
+
+    fn checkpoint(&self) -> Option<String> {
+        Some(json::encode(&(&self.artifact, &self.state, self.step_number)).unwrap())
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), LogParserError> {
+        let (artifact, state, step_number) = try!(decode_state(self.name(), state));
+        self.artifact = artifact;
+        self.state = state;
+        self.step_number = step_number;
+        Ok(())
+    }
+
     fn has_artifact(&self) -> bool {
-        self.artifact.all_errors.len() > 0
+        self.always_emit || self.artifact.all_errors.len() > 0
+    }
+
+    fn truncated(&self) -> bool {
+        self.artifact.errors_truncated
     }
     
     fn get_artifact(&mut self) -> String {
-        let artifact = mem::replace(&mut self.artifact, StepData::new());
+        let artifact = mem::replace(&mut self.artifact, StepData::new(self.normalizer.version()));
         json::encode(&artifact).unwrap()
     }
 }
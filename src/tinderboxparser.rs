@@ -1,5 +1,6 @@
+use events::{EventSink, ParseEvent};
 use logparser::{LogParser, LogParserError};
-use regex::Regex;
+use regex::{Captures, Regex, RegexSet};
 use rustc_serialize::json::{self, Json, ToJson};
 use rustc_serialize::{Encodable, Encoder};
 use std::mem;
@@ -8,32 +9,80 @@ lazy_static! {
     static ref RE_TINDERBOXPRINT: Regex =
         Regex::new(r"TinderboxPrint: ?(?P<line>.*)$").unwrap();
 
-    static ref RE_TALOSRESULT: Regex =
-        Regex::new("^TalosResult: ?(?P<value>.*)").unwrap();
+    static ref DEFAULT_MATCHER: TinderboxMatcher = TinderboxMatcher::new(vec![
+        TinderboxRule::new("^TalosResult: ?(?P<value>.*)",
+                           Some("TalosResult"), ContentType::TalosResult).unwrap(),
+        TinderboxRule::new(r#"<a href=['"](?P<url>http(s)?://.*)['"]>(?P<value>.+)</a>: uploaded"#,
+                           Some("artifact uploaded"), ContentType::Link).unwrap(),
+        TinderboxRule::new(r#"((?P<title>[A-Za-z/\.0-9-_ ]+): )?<a .*href=['"](?P<url>http(s)?://.+)['"].*>(?P<value>.+)</a>"#,
+                           None, ContentType::Link).unwrap(),
+        TinderboxRule::new(r"((?P<title>[A-Za-z/\.0-9-_ ]+): )?(?P<url>http(s)?://.*)",
+                           None, ContentType::Link).unwrap()]).unwrap();
 
-    static ref RE_UPLOADED_TO: Regex =
-        Regex::new(r#"<a href=['"](?P<url>http(s)?://.*)['"]>(?P<value>.+)</a>: uploaded"#).unwrap();
+    static ref RE_BR_ELEMENT: Regex =
+        Regex::new(r"<br/>").unwrap();
 
-    static ref RE_LINK_HTML: Regex =
-        Regex::new(r#"((?P<title>[A-Za-z/\.0-9-_ ]+): )?<a .*href=['"](?P<url>http(s)?://.+)['"].*>(?P<value>.+)</a>"#).unwrap();
+    static ref RE_FRACTION_PAIR: Regex =
+        Regex::new(r"^(?P<title>[A-Za-z][A-Za-z ]*?) (?P<value>\d+ of \d+)$").unwrap();
 
-    static ref RE_LINK_TEXT: Regex =
-        Regex::new(r"((?P<title>[A-Za-z/\.0-9-_ ]+): )?(?P<url>http(s)?://.*)").unwrap();
+    static ref RE_KEY_VALUE: Regex =
+        Regex::new(r"^(?P<title>[A-Za-z][A-Za-z0-9_ ./-]*?):\s*(?P<value>.*)$").unwrap();
 
-    static ref RE_BR_ELEMENT: Regex =
-        Regex::new(r"<br/>").unwrap();
+    static ref RE_HTML_TAG: Regex =
+        Regex::new(r"<(?P<close>/)?(?P<tag>[A-Za-z][A-Za-z0-9]*)(?P<attrs>[^>]*)>").unwrap();
+
+    static ref RE_HTML_SCRIPT: Regex =
+        Regex::new(r"(?is)<script\b.*?</script\s*>|<style\b.*?</style\s*>").unwrap();
+
+    static ref RE_HREF: Regex =
+        Regex::new(r#"href=['"](?P<url>https?://[^'"]+)['"]"#).unwrap();
 
-    static ref TINDERBOX_REGEXPS: [(&'static Regex, Option<&'static str>); 3] =
-        [(&*RE_UPLOADED_TO, Some("artifact uploaded")),
-         (&*RE_LINK_HTML, None),
-         (&*RE_LINK_TEXT, None),];
+    static ref RE_NUMBER: Regex =
+        Regex::new(r"^-?\d+(?:\.\d+)?$").unwrap();
+
+    static ref RE_REVISION: Regex =
+        Regex::new(r"^[0-9a-f]{12}(?:[0-9a-f]{28})?$").unwrap();
+
+    static ref RE_DURATION: Regex =
+        Regex::new(r"^(?P<value>\d+(?:\.\d+)?) ?(?P<unit>ms|s|secs?|seconds?|mins?|minutes?|h|hrs?|hours?)$").unwrap();
+
+    static ref RE_DURATION_CLOCK: Regex =
+        Regex::new(r"^(?P<hours>\d+):(?P<minutes>\d\d):(?P<seconds>\d\d(?:\.\d+)?)$").unwrap();
+
+    static ref RE_FRACTION: Regex =
+        Regex::new(r"^(?P<current>\d+) of (?P<total>\d+)$").unwrap();
 }
 
-#[derive(Clone, Copy)]
-enum ContentType {
+static ALLOWED_HTML_TAGS: [&'static str; 9] =
+    ["a", "b", "br", "code", "em", "i", "p", "span", "strong"];
+
+#[derive(Clone)]
+pub enum ContentType {
     TalosResult,
     Link,
-    RawHtml
+    RawHtml,
+    Text,
+    Number,
+    Revision,
+    Duration,
+    Fraction,
+    Custom(String)
+}
+
+impl ContentType {
+    fn from_name(name: &str) -> ContentType {
+        match name {
+            "TalosResult" => ContentType::TalosResult,
+            "link" => ContentType::Link,
+            "raw_html" => ContentType::RawHtml,
+            "text" => ContentType::Text,
+            "number" => ContentType::Number,
+            "revision" => ContentType::Revision,
+            "duration" => ContentType::Duration,
+            "fraction" => ContentType::Fraction,
+            x => ContentType::Custom(x.into())
+        }
+    }
 }
 
 impl Encodable for ContentType {
@@ -41,13 +90,108 @@ impl Encodable for ContentType {
         s.emit_str(match *self {
             ContentType::TalosResult => "TalosResult",
             ContentType::Link => "link",
-            ContentType::RawHtml => "raw_html"
+            ContentType::RawHtml => "raw_html",
+            ContentType::Text => "text",
+            ContentType::Number => "number",
+            ContentType::Revision => "revision",
+            ContentType::Duration => "duration",
+            ContentType::Fraction => "fraction",
+            ContentType::Custom(ref x) => &**x
         })
     }
 }
 
+/// Remove all but a small set of formatting tags, and all attributes other
+/// than http(s) link targets.
+fn sanitize_html(value: &str) -> String {
+    let value = RE_HTML_SCRIPT.replace_all(value, "");
+    RE_HTML_TAG.replace_all(&*value, |captures: &Captures| {
+        let tag = captures["tag"].to_lowercase();
+        if !ALLOWED_HTML_TAGS.contains(&&*tag) {
+            return String::new();
+        }
+        if captures.name("close").is_some() {
+            return format!("</{}>", tag);
+        }
+        if tag == "a" {
+            if let Some(href) = RE_HREF.captures(&captures["attrs"]) {
+                return format!("<a href=\"{}\">", &href["url"]);
+            }
+        }
+        format!("<{}>", tag)
+    })
+}
+
+/// Reduce the value to plain text. Entities are decoded before the tags are
+/// removed, so that escaped markup doesn't come out as tags.
+fn strip_html(value: &str) -> String {
+    let value = value.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    RE_HTML_TAG.replace_all(&*RE_HTML_SCRIPT.replace_all(&*value, ""), "")
+}
+
+fn parse_duration(value: &str) -> Option<f64> {
+    if let Some(captures) = RE_DURATION.captures(value) {
+        let amount: f64 = captures["value"].parse().unwrap_or(0.0);
+        let scale = match &captures["unit"] {
+            "ms" => 1E-3,
+            "h" | "hr" | "hrs" | "hour" | "hours" => 3600.0,
+            unit if unit.starts_with("m") => 60.0,
+            _ => 1.0
+        };
+        Some(amount * scale)
+    } else if let Some(captures) = RE_DURATION_CLOCK.captures(value) {
+        let hours: f64 = captures["hours"].parse().unwrap_or(0.0);
+        let minutes: f64 = captures["minutes"].parse().unwrap_or(0.0);
+        let seconds: f64 = captures["seconds"].parse().unwrap_or(0.0);
+        Some(hours * 3600.0 + minutes * 60.0 + seconds)
+    } else {
+        None
+    }
+}
+
+/// Work out the most specific type for a TinderboxPrint value. Values with
+/// markup that survives sanitizing are kept as HTML, otherwise the markup is
+/// stripped and the remaining text is checked against the typed formats.
+fn parse_value(value: &str) -> (ContentType, Json) {
+    let text = if RE_HTML_TAG.is_match(value) {
+        let sanitized = sanitize_html(value);
+        if RE_HTML_TAG.is_match(&*sanitized) {
+            return (ContentType::RawHtml, Json::String(sanitized));
+        }
+        strip_html(&*sanitized)
+    } else {
+        strip_html(value)
+    };
+    let text = text.trim();
+
+    if RE_NUMBER.is_match(text) {
+        let number = text.parse::<i64>().map(Json::I64)
+            .or_else(|_| text.parse::<f64>().map(Json::F64));
+        if let Ok(number) = number {
+            return (ContentType::Number, number);
+        }
+    }
+    if RE_REVISION.is_match(text) {
+        return (ContentType::Revision, text.to_json());
+    }
+    if let Some(duration) = parse_duration(text) {
+        return (ContentType::Duration, duration.to_json());
+    }
+    if let Some(captures) = RE_FRACTION.captures(text) {
+        let mut fraction = json::Object::new();
+        fraction.insert("current".into(), Json::U64(captures["current"].parse().unwrap_or(0)));
+        fraction.insert("total".into(), Json::U64(captures["total"].parse().unwrap_or(0)));
+        return (ContentType::Fraction, Json::Object(fraction));
+    }
+    (ContentType::Text, text.to_json())
+}
+
 #[derive(RustcEncodable)]
-struct TinderboxData {
+pub struct TinderboxData {
     title: Option<String>,
     content_type: ContentType,
     url: Option<String>,
@@ -64,18 +208,125 @@ impl TinderboxData {
             value: value
         }
     }
+
+    /// Read an item back from its encoded form.
+    fn from_json(data: &Json) -> Option<TinderboxData> {
+        let string = |key: &str| data.find(key).and_then(|x| x.as_string());
+        match (string("content_type"), data.find("value")) {
+            (Some(content_type), Some(value)) => {
+                Some(TinderboxData::new(string("title"), ContentType::from_name(content_type),
+                                        value.clone(), string("url")))
+            },
+            _ => None
+        }
+    }
+}
+
+/// A rule for extracting a TinderboxPrint line. The pattern may have `title`,
+/// `value` and `url` named groups; if there is no `value` the `url` is used
+/// instead.
+#[derive(Clone)]
+pub struct TinderboxRule {
+    regex: Regex,
+    default_title: Option<String>,
+    content_type: ContentType
+}
+
+impl TinderboxRule {
+    pub fn new(pattern: &str, default_title: Option<&str>,
+               content_type: ContentType) -> Result<TinderboxRule, LogParserError> {
+        Ok(TinderboxRule {
+            regex: try!(Regex::new(pattern).map_err(
+                |e| LogParserError::Other(format!("Invalid TinderboxPrint rule {}: {}", pattern, e)))),
+            default_title: default_title.map(|x| x.into()),
+            content_type: content_type
+        })
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        self.regex.is_match(line)
+    }
+
+    /// Extract the data from a line. The rule must match the line.
+    pub fn extract(&self, line: &str) -> Result<TinderboxData, LogParserError> {
+        let captures = self.regex.captures(line)
+            .expect("Rule doesn't match the line");
+        let title = captures.name("title").or(self.default_title.as_ref().map(|x| &**x));
+        let url = captures.name("url");
+        let value = captures.name("value").or(url);
+        let value = match self.content_type {
+            ContentType::TalosResult => try!(Json::from_str(value.unwrap_or("{}"))),
+            _ => value.to_json()
+        };
+        Ok(TinderboxData::new(title, self.content_type.clone(), value, url))
+    }
+}
+
+/// Set of rules matched in a single pass. Where several rules match, the
+/// one added first wins.
+pub struct TinderboxMatcher {
+    set: RegexSet,
+    rules: Vec<TinderboxRule>
+}
+
+impl TinderboxMatcher {
+    pub fn new(rules: Vec<TinderboxRule>) -> Result<TinderboxMatcher, LogParserError> {
+        let set = try!(RegexSet::new(rules.iter().map(|x| x.regex.as_str())).map_err(
+            |e| LogParserError::Other(format!("Invalid TinderboxPrint rules: {}", e))));
+        Ok(TinderboxMatcher {
+            set: set,
+            rules: rules
+        })
+    }
+
+    pub fn find(&self, line: &str) -> Option<&TinderboxRule> {
+        self.set.matches(line).iter().next().map(|idx| &self.rules[idx])
+    }
+}
+
+/// The built-in rules, in the order they are tried.
+pub fn default_rules() -> Vec<TinderboxRule> {
+    DEFAULT_MATCHER.rules.clone()
 }
 
 pub struct TinderboxParser {
-    artifact: Vec<TinderboxData>
+    artifact: Vec<TinderboxData>,
+    matcher: Option<TinderboxMatcher>,
+    events: EventSink
 }
 
 impl TinderboxParser {
     pub fn new() -> TinderboxParser {
         TinderboxParser {
-            artifact: vec![]
+            artifact: vec![],
+            matcher: None,
+            events: EventSink::none()
         }
     }
+
+    /// Create a parser with extra rules, which take precedence over the
+    /// built-in ones.
+    pub fn with_rules(rules: Vec<TinderboxRule>) -> Result<TinderboxParser, LogParserError> {
+        let mut all_rules = rules;
+        all_rules.extend(DEFAULT_MATCHER.rules.iter().cloned());
+        Ok(TinderboxParser {
+            artifact: vec![],
+            matcher: Some(try!(TinderboxMatcher::new(all_rules))),
+            events: EventSink::none()
+        })
+    }
+
+    fn matcher(&self) -> &TinderboxMatcher {
+        self.matcher.as_ref().unwrap_or(&*DEFAULT_MATCHER)
+    }
+
+    fn add(&mut self, data: TinderboxData, line_number: u32) {
+        self.events.emit(|| ParseEvent::JobDetail {
+            linenumber: line_number,
+            detail: json::encode(&data).unwrap()
+        });
+        self.artifact.push(data);
+    }
 }
 
 impl LogParser for TinderboxParser {
@@ -83,7 +334,7 @@ impl LogParser for TinderboxParser {
         "job_details"
     }
 
-    fn parse_line(&mut self, line: &str, _line_number: u32) -> Result<(), LogParserError> {
+    fn parse_line(&mut self, line: &str, line_number: u32) -> Result<(), LogParserError> {
         if !RE_TINDERBOXPRINT.is_match(line) {
             return Ok(());
         }
@@ -97,52 +348,37 @@ impl LogParser for TinderboxParser {
             return Ok(());
         }
         let line = line.unwrap();
-        if RE_TALOSRESULT.is_match(line) {
-            let title = "TalosResult";
-            //TODO: Need an error here
-            let captures = RE_TALOSRESULT.captures(line);
-            let json_value = captures
-                .expect("RE matched once but not twice")
-                .name("value").unwrap_or("{}");
-            self.artifact.push(TinderboxData::new(Some(title),
-                                                  ContentType::TalosResult,
-                                                  try!(Json::from_str(json_value)),
-                                                  None));
+        if let Some(rule) = self.matcher().find(line) {
+            let artifact = try!(rule.extract(line));
+            self.add(artifact, line_number);
             return Ok(());
         }
 
-        //TODO: replace this loop with RegexSet
-        for &(ref regex, ref default_title) in TINDERBOX_REGEXPS.iter() {
-            if regex.is_match(line) {
-                let captures = regex.captures(line).unwrap();
-                let title = captures.name("title").or(*default_title);
-                let mut value = captures.name("value").map(|x| x.to_owned());
-                let url = captures.name("url");
-                if value.is_none() && url.is_some() {
-                    value = url.map(|x| x.to_owned());
-                }
-                let artifact = TinderboxData::new(title,
-                                                  ContentType::Link,
-                                                  value.to_json(),
-                                                  url);
-                self.artifact.push(artifact);
-                return Ok(());
-            }
-        }
-
-        // Default case
+        // Default case; try to split into a title and a typed value
         let parts: Vec<&str> = RE_BR_ELEMENT.splitn(line, 2).collect();
         let (title, value) = if parts.len() == 1 {
-            (None, line)
+            match RE_FRACTION_PAIR.captures(line).or_else(|| RE_KEY_VALUE.captures(line)) {
+                Some(captures) => (captures.name("title"), captures.name("value").unwrap_or("")),
+                None => (None, line)
+            }
         } else {
             (Some(parts[0]), parts[1])
         };
-        let artifact = TinderboxData::new(title, ContentType::RawHtml, value.to_json(),
-                                          None);
-        self.artifact.push(artifact);
+        let title = title.map(|x| strip_html(x).trim().to_owned());
+        let (content_type, value) = parse_value(value);
+        let artifact = TinderboxData::new(title, content_type, value, None);
+        self.add(artifact, line_number);
         Ok(())
     }
 
+    fn prefilter_literals(&self) -> Option<&'static [&'static str]> {
+        Some(&["TinderboxPrint"])
+    }
+
+    fn set_events(&mut self, events: EventSink) {
+        self.events = events;
+    }
+
     fn has_artifact(&self) -> bool {
         self.artifact.len() > 0
     }
@@ -151,4 +387,20 @@ impl LogParser for TinderboxParser {
         let artifact = mem::replace(&mut self.artifact, vec![]);
         json::encode(&artifact).unwrap()
     }
+
+    fn checkpoint(&self) -> Option<String> {
+        Some(json::encode(&self.artifact).unwrap())
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), LogParserError> {
+        let invalid = || LogParserError::Other(format!("Invalid checkpoint for {}", self.name()));
+        let items = try!(Json::from_str(state));
+        let items = try!(items.as_array().ok_or_else(&invalid));
+        let mut artifact = Vec::with_capacity(items.len());
+        for item in items.iter() {
+            artifact.push(try!(TinderboxData::from_json(item).ok_or_else(&invalid)));
+        }
+        self.artifact = artifact;
+        Ok(())
+    }
 }
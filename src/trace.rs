@@ -0,0 +1,182 @@
+use events::EventSink;
+use logparser::{LogParser, LogParserError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// The time taken by one part of a parse.
+#[derive(Clone, RustcEncodable)]
+pub struct SpanTiming {
+    pub name: String,
+    /// Name of the span this is part of
+    pub parent: Option<String>,
+    pub seconds: f64,
+    /// Number of times the span was entered, for spans made up of many calls
+    pub calls: Option<u64>
+}
+
+/// Timings of the parts of a single parse, as a tree of named spans:
+///
+/// parse
+///   http_fetch
+///   decompress
+///   parser.<name>
+///     parser.<name>.finish_parse
+///     parser.<name>.get_artifact
+///   classify
+pub struct Trace {
+    start: Instant,
+    spans: Mutex<Vec<SpanTiming>>
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace {
+            start: Instant::now(),
+            spans: Mutex::new(vec![])
+        }
+    }
+
+    pub fn record(&self, name: &str, parent: Option<&str>, duration: Duration, calls: Option<u64>) {
+        self.spans.lock().unwrap().push(SpanTiming {
+            name: name.into(),
+            parent: parent.map(|x| x.into()),
+            seconds: seconds(duration),
+            calls: calls
+        });
+    }
+
+    /// Time a closure as a span.
+    pub fn span<T, F: FnOnce() -> T>(&self, name: &str, parent: Option<&str>, f: F) -> T {
+        let start = Instant::now();
+        let rv = f();
+        self.record(name, parent, start.elapsed(), None);
+        rv
+    }
+
+    /// Get the spans, with a span for the whole parse so far first.
+    pub fn spans(&self) -> Vec<SpanTiming> {
+        let spans = self.spans.lock().unwrap();
+        let mut rv = Vec::with_capacity(spans.len() + 1);
+        rv.push(SpanTiming {
+            name: "parse".into(),
+            parent: None,
+            seconds: seconds(self.start.elapsed()),
+            calls: None
+        });
+        rv.extend(spans.iter().cloned());
+        rv
+    }
+}
+
+/// Parser that times each call to the parser it wraps, adding the totals
+/// to a Trace when it is dropped.
+pub struct TracedParser {
+    inner: Box<LogParser>,
+    trace: Arc<Trace>,
+    parse: Duration,
+    calls: u64,
+    finish: Duration,
+    serialize: Duration
+}
+
+impl TracedParser {
+    pub fn new(inner: Box<LogParser>, trace: Arc<Trace>) -> TracedParser {
+        TracedParser {
+            inner: inner,
+            trace: trace,
+            parse: Duration::new(0, 0),
+            calls: 0,
+            finish: Duration::new(0, 0),
+            serialize: Duration::new(0, 0)
+        }
+    }
+}
+
+impl Drop for TracedParser {
+    fn drop(&mut self) {
+        let name = format!("parser.{}", self.inner.name());
+        self.trace.record(&name, Some("parse"), self.parse + self.finish + self.serialize,
+                          Some(self.calls));
+        self.trace.record(&*format!("{}.finish_parse", name), Some(&name), self.finish, None);
+        self.trace.record(&*format!("{}.get_artifact", name), Some(&name), self.serialize, None);
+    }
+}
+
+impl LogParser for TracedParser {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn parse_line(&mut self, line: &str, line_number: u32) -> Result<(), LogParserError> {
+        let start = Instant::now();
+        let rv = self.inner.parse_line(line, line_number);
+        self.parse += start.elapsed();
+        self.calls += 1;
+        rv
+    }
+
+    fn skip_line(&mut self, line: &str, line_number: u32) -> Result<(), LogParserError> {
+        let start = Instant::now();
+        let rv = self.inner.skip_line(line, line_number);
+        self.parse += start.elapsed();
+        self.calls += 1;
+        rv
+    }
+
+    fn get_artifact(&mut self) -> String {
+        let start = Instant::now();
+        let rv = self.inner.get_artifact();
+        self.serialize += start.elapsed();
+        rv
+    }
+
+    fn finish_parse(&mut self, last_line_number: u32) {
+        let start = Instant::now();
+        self.inner.finish_parse(last_line_number);
+        self.finish += start.elapsed();
+    }
+
+    fn complete(&self) -> bool {
+        self.inner.complete()
+    }
+
+    fn has_artifact(&self) -> bool {
+        self.inner.has_artifact()
+    }
+
+    fn truncated(&self) -> bool {
+        self.inner.truncated()
+    }
+
+    fn line_limit(&self) -> Option<u32> {
+        self.inner.line_limit()
+    }
+
+    fn byte_limit(&self) -> Option<u64> {
+        self.inner.byte_limit()
+    }
+
+    fn prefilter_literals(&self) -> Option<&'static [&'static str]> {
+        self.inner.prefilter_literals()
+    }
+
+    fn set_events(&mut self, events: EventSink) {
+        self.inner.set_events(events)
+    }
+
+    fn snapshot(&self) -> Option<String> {
+        self.inner.snapshot()
+    }
+
+    fn checkpoint(&self) -> Option<String> {
+        self.inner.checkpoint()
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), LogParserError> {
+        self.inner.restore(state)
+    }
+}
@@ -0,0 +1,444 @@
+extern crate logparser;
+extern crate rustc_serialize;
+
+use logparser::amqp::{AmqpConfig, AmqpSource};
+use logparser::daemon::{Delivery, JobSource};
+use logparser::logparser::LogParserError;
+use rustc_serialize::json::Json;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// A stand-in for an AMQP 0-9-1 broker that follows a script, checking the
+// frames the client sends.
+struct Broker {
+    stream: TcpStream
+}
+
+fn short(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&[(value >> 8) as u8, value as u8]);
+}
+
+fn long(buf: &mut Vec<u8>, value: u32) {
+    short(buf, (value >> 16) as u16);
+    short(buf, value as u16);
+}
+
+fn longlong(buf: &mut Vec<u8>, value: u64) {
+    long(buf, (value >> 32) as u32);
+    long(buf, value as u32);
+}
+
+fn shortstr(buf: &mut Vec<u8>, value: &str) {
+    buf.push(value.len() as u8);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn read_short(data: &[u8], pos: &mut usize) -> u16 {
+    *pos += 2;
+    (data[*pos - 2] as u16) << 8 | data[*pos - 1] as u16
+}
+
+fn read_longlong(data: &[u8], pos: &mut usize) -> u64 {
+    let mut rv = 0;
+    for _ in 0..8 {
+        rv = rv << 8 | data[*pos] as u64;
+        *pos += 1;
+    }
+    rv
+}
+
+fn read_shortstr(data: &[u8], pos: &mut usize) -> String {
+    let len = data[*pos] as usize;
+    *pos += len + 1;
+    String::from_utf8(data[*pos - len..*pos].to_vec()).unwrap()
+}
+
+fn method(class: u16, method: u16) -> Vec<u8> {
+    let mut buf = vec![];
+    short(&mut buf, class);
+    short(&mut buf, method);
+    buf
+}
+
+impl Broker {
+    fn send_frame(&mut self, kind: u8, channel: u16, payload: &[u8]) {
+        let mut buf = vec![kind];
+        short(&mut buf, channel);
+        long(&mut buf, payload.len() as u32);
+        buf.extend_from_slice(payload);
+        buf.push(0xCE);
+        self.stream.write_all(&buf).unwrap();
+    }
+
+    fn read_frame(&mut self) -> (u8, u16, Vec<u8>) {
+        let mut header = [0; 7];
+        self.stream.read_exact(&mut header).unwrap();
+        let size = ((header[3] as usize) << 24) | ((header[4] as usize) << 16) |
+            ((header[5] as usize) << 8) | header[6] as usize;
+        let mut payload = vec![0; size + 1];
+        self.stream.read_exact(&mut payload).unwrap();
+        assert_eq!(payload.pop(), Some(0xCE));
+        (header[0], (header[1] as u16) << 8 | header[2] as u16, payload)
+    }
+
+    /// Read a method frame, checking the channel and method, and return
+    /// its arguments.
+    fn expect(&mut self, channel: u16, class: u16, id: u16) -> Vec<u8> {
+        let (kind, frame_channel, payload) = self.read_frame();
+        assert_eq!(kind, 1);
+        assert_eq!(frame_channel, channel);
+        let mut pos = 0;
+        assert_eq!((read_short(&payload, &mut pos), read_short(&payload, &mut pos)), (class, id));
+        payload[4..].to_vec()
+    }
+
+    /// Run the client's side of opening a connection and consuming from
+    /// the queue, returning the credentials and vhost it gave.
+    fn handshake(&mut self, queue: &str) -> (Vec<u8>, String) {
+        let mut header = [0; 8];
+        self.stream.read_exact(&mut header).unwrap();
+        assert_eq!(&header, b"AMQP\x00\x00\x09\x01");
+
+        let mut start = method(10, 10);
+        start.extend_from_slice(&[0, 9]);
+        long(&mut start, 0);
+        long(&mut start, 5);
+        start.extend_from_slice(b"PLAIN");
+        long(&mut start, 5);
+        start.extend_from_slice(b"en_US");
+        self.send_frame(1, 0, &start);
+        let start_ok = self.expect(0, 10, 11);
+        let mut pos = 4;
+        assert_eq!(read_shortstr(&start_ok, &mut pos), "PLAIN");
+        let len = ((start_ok[pos] as usize) << 24) | ((start_ok[pos + 1] as usize) << 16) |
+            ((start_ok[pos + 2] as usize) << 8) | start_ok[pos + 3] as usize;
+        let credentials = start_ok[pos + 4..pos + 4 + len].to_vec();
+
+        let mut tune = method(10, 30);
+        short(&mut tune, 0);
+        long(&mut tune, 4096);
+        short(&mut tune, 60);
+        self.send_frame(1, 0, &tune);
+        let tune_ok = self.expect(0, 10, 31);
+        let mut pos = 2;
+        assert_eq!(&tune_ok[pos..pos + 4], &[0u8, 0, 16, 0][..]);
+        pos += 4;
+        // Heartbeats are turned off
+        assert_eq!(read_short(&tune_ok, &mut pos), 0);
+
+        let open = self.expect(0, 10, 40);
+        let vhost = read_shortstr(&open, &mut 0);
+        let mut open_ok = method(10, 41);
+        shortstr(&mut open_ok, "");
+        self.send_frame(1, 0, &open_ok);
+
+        self.expect(1, 20, 10);
+        let mut channel_open_ok = method(20, 11);
+        long(&mut channel_open_ok, 0);
+        self.send_frame(1, 1, &channel_open_ok);
+        self.expect(1, 85, 10);
+        self.send_frame(1, 1, &method(85, 11));
+        self.expect(1, 60, 10);
+        self.send_frame(1, 1, &method(60, 11));
+        // The job queue must already exist; the dead-letter queue is created
+        self.expect_declare(queue, 1);
+        self.expect_declare("jobs.dead", 2);
+        let consume = self.expect(1, 60, 20);
+        assert_eq!(read_shortstr(&consume, &mut 2), queue);
+        let mut consume_ok = method(60, 21);
+        shortstr(&mut consume_ok, "consumer");
+        self.send_frame(1, 1, &consume_ok);
+        (credentials, vhost)
+    }
+
+    fn expect_declare(&mut self, queue: &str, flags: u8) {
+        let declare = self.expect(1, 50, 10);
+        let mut pos = 2;
+        assert_eq!(read_shortstr(&declare, &mut pos), queue);
+        assert_eq!(declare[pos], flags);
+        let mut declare_ok = method(50, 11);
+        shortstr(&mut declare_ok, queue);
+        long(&mut declare_ok, 0);
+        long(&mut declare_ok, 0);
+        self.send_frame(1, 1, &declare_ok);
+    }
+
+    fn deliver(&mut self, tag: u64, body: &[u8]) {
+        self.deliver_with_attempts(tag, body, None);
+    }
+
+    /// Deliver a message, with the attempts header if attempts is given.
+    fn deliver_with_attempts(&mut self, tag: u64, body: &[u8], attempts: Option<u32>) {
+        let mut deliver = method(60, 60);
+        shortstr(&mut deliver, "consumer");
+        longlong(&mut deliver, tag);
+        deliver.push(0);
+        shortstr(&mut deliver, "");
+        shortstr(&mut deliver, "jobs");
+        self.send_frame(1, 1, &deliver);
+        let mut header = vec![];
+        short(&mut header, 60);
+        short(&mut header, 0);
+        longlong(&mut header, body.len() as u64);
+        match attempts {
+            Some(attempts) => {
+                // Content type, then headers with another field before the attempts
+                short(&mut header, 0x8000 | 0x2000);
+                shortstr(&mut header, "application/json");
+                let mut table = vec![];
+                shortstr(&mut table, "source");
+                table.push(b'S');
+                long(&mut table, 4);
+                table.extend_from_slice(b"test");
+                shortstr(&mut table, "x-logparser-attempts");
+                table.push(b'I');
+                long(&mut table, attempts);
+                long(&mut header, table.len() as u32);
+                header.extend_from_slice(&table);
+            },
+            None => short(&mut header, 0)
+        }
+        self.send_frame(2, 1, &header);
+        // Split the body, as a broker does for large messages
+        let mid = body.len() / 2;
+        self.send_frame(3, 1, &body[..mid]);
+        self.send_frame(3, 1, &body[mid..]);
+    }
+
+    /// Read a published message, returning the routing key, content header
+    /// and body.
+    fn expect_publish(&mut self) -> (String, Vec<u8>, Vec<u8>) {
+        let publish = self.expect(1, 60, 40);
+        let mut pos = 2;
+        assert_eq!(read_shortstr(&publish, &mut pos), "");
+        let routing_key = read_shortstr(&publish, &mut pos);
+        // Mandatory is set
+        assert_eq!(publish[pos], 1);
+        let (kind, _, header) = self.read_frame();
+        assert_eq!(kind, 2);
+        let size = read_longlong(&header, &mut 4) as usize;
+        let mut body = vec![];
+        while body.len() < size {
+            let (kind, _, chunk) = self.read_frame();
+            assert_eq!(kind, 3);
+            assert!(chunk.len() <= 4096 - 8);
+            body.extend_from_slice(&chunk);
+        }
+        (routing_key, header, body)
+    }
+
+    fn confirm(&mut self, ack: bool, tag: u64) {
+        let mut confirm = if ack { method(60, 80) } else { method(60, 120) };
+        longlong(&mut confirm, tag);
+        confirm.push(0);
+        self.send_frame(1, 1, &confirm);
+    }
+
+    /// Read a basic.ack or basic.reject from the client, returning the
+    /// method id, delivery tag and flags.
+    fn expect_response(&mut self) -> (u16, u64, u8) {
+        let (kind, _, payload) = self.read_frame();
+        assert_eq!(kind, 1);
+        let mut pos = 0;
+        assert_eq!(read_short(&payload, &mut pos), 60);
+        let id = read_short(&payload, &mut pos);
+        let tag = read_longlong(&payload, &mut pos);
+        (id, tag, payload[pos])
+    }
+}
+
+fn start_broker<F>(script: F) -> (u16, JoinHandle<()>)
+    where F: FnOnce(&mut Broker) + Send + 'static {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        script(&mut Broker {
+            stream: stream
+        });
+    });
+    (port, handle)
+}
+
+fn connect(port: u16) -> AmqpSource {
+    let mut config = AmqpConfig::new("127.0.0.1", "jobs", "jobs.dead");
+    config.port = port;
+    config.poll_interval = Duration::from_millis(50);
+    AmqpSource::connect(config).unwrap()
+}
+
+fn next_delivery(source: &mut AmqpSource) -> Delivery {
+    for _ in 0..100 {
+        if let Some(delivery) = source.next().unwrap() {
+            return delivery;
+        }
+    }
+    panic!("No message was delivered");
+}
+
+#[test]
+fn handshake_uses_configured_credentials_and_vhost() {
+    let (port, broker) = start_broker(|broker| {
+        let (credentials, vhost) = broker.handshake("jobs");
+        assert_eq!(credentials, b"\0parser\0secret");
+        assert_eq!(vhost, "/logs");
+    });
+    let mut config = AmqpConfig::new("127.0.0.1", "jobs", "jobs.dead");
+    config.port = port;
+    config.user = "parser".into();
+    config.password = "secret".into();
+    config.vhost = "/logs".into();
+    AmqpSource::connect(config).unwrap();
+    broker.join().unwrap();
+}
+
+#[test]
+fn deliver_and_ack() {
+    let (port, broker) = start_broker(|broker| {
+        broker.handshake("jobs");
+        broker.deliver(7, b"{\"job_id\": \"1\"}");
+        assert_eq!(broker.expect_response(), (80, 7, 0));
+    });
+    let mut source = connect(port);
+    let delivery = next_delivery(&mut source);
+    assert_eq!(delivery.id, 7);
+    assert_eq!(delivery.body, b"{\"job_id\": \"1\"}");
+    source.ack(delivery.id).unwrap();
+    broker.join().unwrap();
+}
+
+#[test]
+fn requeue_rejects_with_requeue() {
+    let (port, broker) = start_broker(|broker| {
+        broker.handshake("jobs");
+        broker.deliver(3, b"{}");
+        assert_eq!(broker.expect_response(), (90, 3, 1));
+    });
+    let mut source = connect(port);
+    let delivery = next_delivery(&mut source);
+    assert_eq!(delivery.attempts, 0);
+    source.requeue(delivery.id, 0).unwrap();
+    assert!(source.ack(delivery.id).is_err());
+    broker.join().unwrap();
+}
+
+#[test]
+fn requeue_with_attempts_publishes_copy() {
+    let (port, broker) = start_broker(|broker| {
+        broker.handshake("jobs");
+        broker.deliver_with_attempts(4, b"{\"job_id\": \"1\"}", Some(2));
+        let (routing_key, header, body) = broker.expect_publish();
+        assert_eq!(routing_key, "jobs");
+        assert_eq!(body, b"{\"job_id\": \"1\"}");
+        let mut attempts = b"\x14x-logparser-attemptsI".to_vec();
+        long(&mut attempts, 3);
+        assert!(header.windows(attempts.len()).any(|x| x == &attempts[..]));
+        broker.confirm(true, 1);
+        assert_eq!(broker.expect_response(), (80, 4, 0));
+    });
+    let mut source = connect(port);
+    let delivery = next_delivery(&mut source);
+    assert_eq!(delivery.attempts, 2);
+    source.requeue(delivery.id, 3).unwrap();
+    broker.join().unwrap();
+}
+
+#[test]
+fn dead_letter_acks_after_confirm() {
+    let (port, broker) = start_broker(|broker| {
+        broker.handshake("jobs");
+        broker.deliver(1, b"{\"job_id\": \"broken\"}");
+        let (routing_key, _, body) = broker.expect_publish();
+        assert_eq!(routing_key, "jobs.dead");
+        let body = Json::from_str(&String::from_utf8(body).unwrap()).unwrap();
+        assert_eq!(body.find("message").and_then(|x| x.as_string()), Some("{\"job_id\": \"broken\"}"));
+        assert_eq!(body.find("error").and_then(|x| x.as_string()), Some("OtherError: Bad log"));
+        // A message delivered before the confirm is kept for the next call
+        broker.deliver(2, b"{\"job_id\": \"next\"}");
+        broker.confirm(true, 1);
+        assert_eq!(broker.expect_response(), (80, 1, 0));
+    });
+    let mut source = connect(port);
+    let delivery = next_delivery(&mut source);
+    source.dead_letter(delivery.id, &LogParserError::Other("Bad log".into())).unwrap();
+    assert_eq!(next_delivery(&mut source).id, 2);
+    broker.join().unwrap();
+}
+
+#[test]
+fn dead_letter_not_acked_when_publish_fails() {
+    let (port, broker) = start_broker(|broker| {
+        broker.handshake("jobs");
+        broker.deliver(1, b"{}");
+        broker.expect_publish();
+        broker.confirm(false, 1);
+        // The client mustn't ack the original message
+        let mut buf = [0; 1];
+        assert_eq!(broker.stream.read(&mut buf).unwrap(), 0);
+    });
+    {
+        let mut source = connect(port);
+        let delivery = next_delivery(&mut source);
+        assert!(source.dead_letter(delivery.id, &LogParserError::Other("Bad log".into())).is_err());
+    }
+    broker.join().unwrap();
+}
+
+#[test]
+fn dead_letter_not_acked_when_returned() {
+    let (port, broker) = start_broker(|broker| {
+        broker.handshake("jobs");
+        broker.deliver(1, b"{}");
+        let (_, _, body) = broker.expect_publish();
+        let mut basic_return = method(60, 50);
+        short(&mut basic_return, 312);
+        shortstr(&mut basic_return, "NO_ROUTE");
+        shortstr(&mut basic_return, "");
+        shortstr(&mut basic_return, "jobs.dead");
+        broker.send_frame(1, 1, &basic_return);
+        let mut header = vec![];
+        short(&mut header, 60);
+        short(&mut header, 0);
+        longlong(&mut header, body.len() as u64);
+        short(&mut header, 0);
+        broker.send_frame(2, 1, &header);
+        broker.send_frame(3, 1, &body);
+        // The broker acks returned messages
+        broker.confirm(true, 1);
+        let mut buf = [0; 1];
+        assert_eq!(broker.stream.read(&mut buf).unwrap(), 0);
+    });
+    {
+        let mut source = connect(port);
+        let delivery = next_delivery(&mut source);
+        let result = source.dead_letter(delivery.id, &LogParserError::Other("Bad log".into()));
+        assert!(result.unwrap_err().description().contains("NO_ROUTE"));
+    }
+    broker.join().unwrap();
+}
+
+#[test]
+fn broker_close_is_an_error() {
+    let (port, broker) = start_broker(|broker| {
+        broker.handshake("jobs");
+        let mut close = method(10, 50);
+        short(&mut close, 320);
+        shortstr(&mut close, "CONNECTION_FORCED");
+        short(&mut close, 0);
+        short(&mut close, 0);
+        broker.send_frame(1, 0, &close);
+    });
+    let mut source = connect(port);
+    let mut result = source.next();
+    for _ in 0..100 {
+        match result {
+            Ok(None) => result = source.next(),
+            _ => break
+        }
+    }
+    assert!(result.is_err());
+    broker.join().unwrap();
+}
@@ -0,0 +1,93 @@
+extern crate flate2;
+extern crate logparser;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use logparser::ParseOptions;
+use logparser::checkpoint::{parse_log_checkpointed, Checkpoint, CheckpointedParse};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener};
+use std::thread;
+use std::time::Duration;
+
+fn gzipped_log() -> Vec<u8> {
+    let mut log = String::new();
+    for step in 0..10 {
+        log.push_str(&*format!("========= Started step {} (results: 0, elapsed: 0 secs) \
+                                (at 2016-01-01 00:00:{:02}.000000) =========\n", step, step * 2));
+        log.push_str("Running tests\n");
+        log.push_str(&*format!("TEST-UNEXPECTED-FAIL | test_{}.js | Assertion failed\n", step));
+        log.push_str("Tests finished\n");
+        log.push_str(&*format!("========= Finished step {} (results: 2, elapsed: 1 secs) \
+                                (at 2016-01-01 00:00:{:02}.000000) =========\n", step, step * 2 + 1));
+    }
+    let mut encoder = GzEncoder::new(vec![], Compression::Default);
+    encoder.write_all(log.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Start a stand-in HTTP server that answers every request with the log,
+/// returning its URL. It runs until the tests exit.
+fn serve(log: Vec<u8>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://127.0.0.1:{}/log.txt.gz", listener.local_addr().unwrap().port());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            stream.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).unwrap();
+                assert!(len > 0, "Connection closed before the end of the request");
+                request.extend_from_slice(&buf[..len]);
+            }
+            write!(stream, "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+                   log.len()).unwrap();
+            stream.write_all(&log).unwrap();
+            stream.shutdown(Shutdown::Both).unwrap();
+        }
+    });
+    url
+}
+
+/// The artifacts, other than the parse metadata, which describes the last
+/// part of the parse only.
+fn log_artifacts(artifacts: Vec<(&'static str, String)>) -> Vec<(&'static str, String)> {
+    artifacts.into_iter().filter(|x| x.0 != "parse_metadata").collect()
+}
+
+#[test]
+fn resumed_parse_matches_uninterrupted_parse() {
+    let url = serve(gzipped_log());
+
+    let expected = match parse_log_checkpointed(&url, "test", &ParseOptions::new(), None).unwrap() {
+        CheckpointedParse::Complete(artifacts) => log_artifacts(artifacts),
+        CheckpointedParse::Stopped(_) => panic!("Parse without limits stopped")
+    };
+
+    let mut options = ParseOptions::new();
+    options.limits.max_lines = Some(12);
+    let mut checkpoint: Option<Checkpoint> = None;
+    let mut runs = 0;
+    let artifacts;
+    loop {
+        runs += 1;
+        assert!(runs <= 10, "Parse isn't making progress");
+        match parse_log_checkpointed(&url, "test", &options, checkpoint.as_ref()).unwrap() {
+            CheckpointedParse::Complete(x) => {
+                artifacts = x;
+                break;
+            },
+            CheckpointedParse::Stopped(stopped) => {
+                // Carry on from the checkpoint as saved, as another process would
+                let line_number = checkpoint.as_ref().map(|x| x.line_number).unwrap_or(0);
+                assert_eq!(stopped.line_number, line_number + 12);
+                checkpoint = Some(Checkpoint::from_json(&stopped.to_json()).unwrap());
+            }
+        }
+    }
+    // The limit applies to each run, rather than the whole log
+    assert_eq!(runs, 5);
+    assert_eq!(log_artifacts(artifacts), expected);
+}
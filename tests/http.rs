@@ -0,0 +1,213 @@
+extern crate logparser;
+
+use logparser::http::{get, RetryPolicy};
+use logparser::limits::{CancelHandle, LimitState, ParseLimits};
+use logparser::logparser::LogParserError;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Start a stand-in HTTP server that answers each connection with the next
+/// of the given raw responses, then closes it. Returns the URL and a handle
+/// that gives the requests it received.
+fn serve(responses: Vec<String>) -> (String, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://127.0.0.1:{}/log.txt", listener.local_addr().unwrap().port());
+    let handle = thread::spawn(move || {
+        let mut requests = vec![];
+        for response in responses.iter() {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).unwrap();
+                assert!(len > 0, "Connection closed before the end of the request");
+                request.extend_from_slice(&buf[..len]);
+            }
+            requests.push(String::from_utf8(request).unwrap().to_lowercase());
+            stream.write_all(response.as_bytes()).unwrap();
+            stream.shutdown(Shutdown::Both).unwrap();
+        }
+        requests
+    });
+    (url, handle)
+}
+
+fn response(status: &str, headers: &[&str], content_length: usize, body: &str) -> String {
+    let mut rv = format!("HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+                         status, content_length);
+    for header in headers.iter() {
+        rv.push_str(header);
+        rv.push_str("\r\n");
+    }
+    rv.push_str("\r\n");
+    rv.push_str(body);
+    rv
+}
+
+fn policy() -> RetryPolicy {
+    RetryPolicy {
+        max_retries: 3,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(10),
+        check_redirect: None
+    }
+}
+
+fn unlimited() -> Arc<LimitState> {
+    Arc::new(LimitState::unlimited())
+}
+
+fn timeout() -> Option<Duration> {
+    Some(Duration::new(5, 0))
+}
+
+#[test]
+fn retries_server_errors() {
+    let (url, server) = serve(vec![response("503 Service Unavailable", &[], 0, ""),
+                                   response("500 Internal Server Error", &[], 0, ""),
+                                   response("200 OK", &[], 6, "hello\n")]);
+    let mut download = get(&url, "test", timeout(), &policy(), unlimited()).unwrap();
+    let mut body = String::new();
+    download.read_to_string(&mut body).unwrap();
+    assert_eq!(body, "hello\n");
+    assert_eq!(download.stats().retries(), 2);
+    assert_eq!(server.join().unwrap().len(), 3);
+}
+
+#[test]
+fn gives_up_after_max_retries() {
+    let (url, server) = serve(vec![response("503 Service Unavailable", &[], 0, ""); 4]);
+    match get(&url, "test", timeout(), &policy(), unlimited()) {
+        Err(LogParserError::Http(status)) => assert_eq!(status.to_u16(), 503),
+        _ => panic!("Expected a 503 error")
+    }
+    assert_eq!(server.join().unwrap().len(), 4);
+}
+
+#[test]
+fn does_not_retry_client_errors() {
+    let (url, server) = serve(vec![response("404 Not Found", &[], 0, "")]);
+    match get(&url, "test", timeout(), &policy(), unlimited()) {
+        Err(LogParserError::Http(status)) => assert_eq!(status.to_u16(), 404),
+        _ => panic!("Expected a 404 error")
+    }
+    assert_eq!(server.join().unwrap().len(), 1);
+}
+
+#[test]
+fn resumes_dropped_connection_from_same_version() {
+    // The first connection drops after 6 of the 12 bytes
+    let (url, server) = serve(vec![
+        response("200 OK", &["ETag: \"v1\""], 12, "first\n"),
+        response("206 Partial Content", &["ETag: \"v1\"", "Content-Range: bytes 6-11/12"], 6, "later\n")]);
+    let mut download = get(&url, "test", timeout(), &policy(), unlimited()).unwrap();
+    let mut body = String::new();
+    download.read_to_string(&mut body).unwrap();
+    assert_eq!(body, "first\nlater\n");
+    assert_eq!(download.stats().resumes(), 1);
+    let requests = server.join().unwrap();
+    assert!(!requests[0].contains("range:"));
+    assert!(requests[1].contains("\r\nrange: bytes=6-\r\n"));
+    assert!(requests[1].contains("\r\nif-range: \"v1\"\r\n"));
+}
+
+#[test]
+fn resume_uses_date_without_strong_etag() {
+    let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+    let last_modified = format!("Last-Modified: {}", date);
+    let (url, server) = serve(vec![
+        response("200 OK", &["ETag: W/\"v1\"", &*last_modified], 12, "first\n"),
+        response("206 Partial Content", &["Content-Range: bytes 6-11/12"], 6, "later\n")]);
+    let mut download = get(&url, "test", timeout(), &policy(), unlimited()).unwrap();
+    let mut body = String::new();
+    download.read_to_string(&mut body).unwrap();
+    assert_eq!(body, "first\nlater\n");
+    let requests = server.join().unwrap();
+    assert!(requests[1].contains(&*format!("\r\nif-range: {}\r\n", date.to_lowercase())));
+}
+
+#[test]
+fn fails_when_log_changes_during_download() {
+    // The server sends the whole of a new version instead of the range
+    let (url, server) = serve(vec![
+        response("200 OK", &["ETag: \"v1\""], 12, "first\n"),
+        response("200 OK", &["ETag: \"v2\""], 12, "other\nlines\n")]);
+    let mut download = get(&url, "test", timeout(), &policy(), unlimited()).unwrap();
+    let mut body = String::new();
+    assert!(download.read_to_string(&mut body).is_err());
+    assert_eq!(server.join().unwrap().len(), 2);
+}
+
+#[test]
+fn skips_prefix_when_range_is_ignored_without_validators() {
+    let (url, server) = serve(vec![
+        response("200 OK", &[], 12, "first\n"),
+        response("200 OK", &[], 12, "first\nlater\n")]);
+    let mut download = get(&url, "test", timeout(), &policy(), unlimited()).unwrap();
+    let mut body = String::new();
+    download.read_to_string(&mut body).unwrap();
+    assert_eq!(body, "first\nlater\n");
+    server.join().unwrap();
+}
+
+fn slow_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_retries: 3,
+        initial_backoff: Duration::new(10, 0),
+        max_backoff: Duration::new(10, 0),
+        check_redirect: None
+    }
+}
+
+fn with_deadline(deadline: Duration) -> Arc<LimitState> {
+    let mut limits = ParseLimits::new();
+    limits.deadline = Some(deadline);
+    Arc::new(LimitState::new(&limits))
+}
+
+#[test]
+fn deadline_cuts_retry_backoff_short() {
+    let (url, server) = serve(vec![response("503 Service Unavailable", &[], 0, "")]);
+    let start = Instant::now();
+    assert!(get(&url, "test", timeout(), &slow_policy(), with_deadline(Duration::from_millis(300))).is_err());
+    assert!(start.elapsed() < Duration::new(5, 0));
+    assert_eq!(server.join().unwrap().len(), 1);
+}
+
+#[test]
+fn cancel_stops_retries() {
+    let (url, server) = serve(vec![response("503 Service Unavailable", &[], 0, "")]);
+    let cancel = CancelHandle::new();
+    let mut limits = ParseLimits::new();
+    limits.cancel = Some(cancel.clone());
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        cancel.cancel();
+    });
+    let start = Instant::now();
+    assert!(get(&url, "test", timeout(), &slow_policy(), Arc::new(LimitState::new(&limits))).is_err());
+    assert!(start.elapsed() < Duration::new(5, 0));
+    canceller.join().unwrap();
+    assert_eq!(server.join().unwrap().len(), 1);
+}
+
+#[test]
+fn deadline_limits_wait_for_headers() {
+    // A server that accepts the connection but never answers
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://127.0.0.1:{}/log.txt", listener.local_addr().unwrap().port());
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        thread::sleep(Duration::new(3, 0));
+        drop(stream);
+    });
+    let start = Instant::now();
+    assert!(get(&url, "test", Some(Duration::new(30, 0)), &slow_policy(),
+                with_deadline(Duration::from_millis(300))).is_err());
+    assert!(start.elapsed() < Duration::new(3, 0));
+    server.join().unwrap();
+}
@@ -0,0 +1,44 @@
+extern crate logparser;
+
+use logparser::limits::LimitState;
+use logparser::{default_parsers, parse_reader, parse_reader_parallel};
+use std::io::Cursor;
+use std::sync::Arc;
+
+/// A log long enough to be split into several batches by the pipeline.
+fn log() -> String {
+    let mut log = String::new();
+    for step in 0..50 {
+        log.push_str(&*format!("========= Started step {} (results: 0, elapsed: 0 secs) \
+                                (at 2016-01-01 00:{:02}:00.000000) =========\n", step, step));
+        for line in 0..200 {
+            let line_number = step * 200 + line;
+            match line % 50 {
+                10 => log.push_str(&*format!("TEST-UNEXPECTED-FAIL | test_{}.js | Assertion failed\n",
+                                             line_number)),
+                20 => log.push_str(&*format!("TinderboxPrint: result {}<br/>{}\n", line_number, step)),
+                30 => log.push_str(&*format!("REFTEST TEST-UNEXPECTED-FAIL | file:///test_{}.html | \
+                                              image comparison\n", line_number)),
+                40 => log.push_str("REFTEST   IMAGE 1 (TEST): data:image/png;base64,iVBORw0KGgo=\n"),
+                _ => log.push_str(&*format!("INFO - line {} of the log\n", line_number))
+            }
+        }
+        log.push_str(&*format!("========= Finished step {} (results: 2, elapsed: 1 secs) \
+                                (at 2016-01-01 00:{:02}:30.000000) =========\n", step, step));
+    }
+    log
+}
+
+#[test]
+fn parallel_parse_matches_sequential_parse() {
+    let log = log();
+    let (sequential, sequential_failures) =
+        parse_reader(Cursor::new(log.clone()), default_parsers(), &LimitState::unlimited(), false)
+        .unwrap();
+    let (parallel, parallel_failures) =
+        parse_reader_parallel(Cursor::new(log), default_parsers(), Arc::new(LimitState::unlimited()), false)
+        .unwrap();
+    assert!(sequential.len() > 1);
+    assert_eq!(parallel, sequential);
+    assert_eq!(parallel_failures.len(), sequential_failures.len());
+}
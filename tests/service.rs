@@ -0,0 +1,100 @@
+extern crate logparser;
+extern crate rustc_serialize;
+
+use logparser::service::{serve, ServiceOptions};
+use rustc_serialize::json::Json;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+fn start(options: ServiceOptions) -> SocketAddr {
+    let listening = serve("127.0.0.1:0", options).unwrap();
+    let addr = listening.socket;
+    // Dropping the server waits for it to stop, so leave it running until the tests exit
+    mem::forget(listening);
+    addr
+}
+
+/// Send a request to the service, returning the status and body.
+fn request(addr: SocketAddr, path: &str, body: &[u8]) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::new(10, 0))).unwrap();
+    write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+           path, addr, body.len()).unwrap();
+    stream.write_all(body).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response[response.find("\r\n\r\n").unwrap() + 4..].to_string();
+    (status, body)
+}
+
+/// Start a stand-in log server that answers one request with a redirect.
+fn redirect_to(location: &str) -> (u16, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let response = format!("HTTP/1.1 302 Found\r\nConnection: close\r\nContent-Length: 0\r\n\
+                            Location: {}\r\n\r\n", location);
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = vec![];
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let len = stream.read(&mut buf).unwrap();
+            assert!(len > 0, "Connection closed before the end of the request");
+            request.extend_from_slice(&buf[..len]);
+        }
+        stream.write_all(response.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Both).unwrap();
+    });
+    (port, handle)
+}
+
+#[test]
+fn url_must_be_on_allowed_host() {
+    let addr = start(ServiceOptions::new());
+    let (status, body) = request(addr, "/parse?url=http://127.0.0.1:1/log.txt", b"");
+    assert_eq!(status, 403);
+    assert!(body.contains("can't be fetched"));
+    let (status, _) = request(addr, "/parse?url=http://archive.mozilla.org.example.com/log.txt", b"");
+    assert_eq!(status, 403);
+}
+
+#[test]
+fn redirect_must_be_on_allowed_host() {
+    let internal = TcpListener::bind("127.0.0.1:0").unwrap();
+    let internal_url = format!("http://127.0.0.1:{}/secret", internal.local_addr().unwrap().port());
+    let (port, log_server) = redirect_to(&internal_url);
+    let mut options = ServiceOptions::new();
+    options.allowed_hosts = vec!["localhost".into()];
+    let addr = start(options);
+
+    let (status, body) = request(addr, &*format!("/parse?url=http://localhost:{}/log.txt", port), b"");
+    log_server.join().unwrap();
+    assert_eq!(status, 500);
+    assert!(body.contains("can't be fetched"));
+    // The redirect wasn't followed
+    internal.set_nonblocking(true).unwrap();
+    assert_eq!(internal.accept().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn parses_uploaded_log() {
+    let addr = start(ServiceOptions::new());
+    let (status, body) = request(addr, "/parse?parsers=step_data", b"first line\nsecond line\n");
+    assert_eq!(status, 200);
+    let artifacts = Json::from_str(&body).unwrap();
+    let metadata = artifacts.find("parse_metadata").unwrap();
+    assert_eq!(metadata.find("complete"), Some(&Json::Boolean(true)));
+}
+
+#[test]
+fn rejects_upload_over_limit() {
+    let mut options = ServiceOptions::new();
+    options.max_body_bytes = 16;
+    let addr = start(options);
+    let (status, _) = request(addr, "/parse", &[b'x'; 64]);
+    assert_eq!(status, 413);
+}